@@ -1,9 +1,18 @@
-use memmap::MmapOptions;
+use memmap::{Mmap, MmapOptions};
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU8, Ordering as AtomicOrdering};
 
-use clap::Parser;
+use clap::{ArgAction, Parser, ValueEnum};
+use suputils::temp_file;
 
 #[derive(Parser)]
 #[command(name = "topk")]
@@ -11,7 +20,8 @@ use clap::Parser;
 #[command(version = "0.1.0")]
 #[command(
     about = "Perform binary search to query lines that match the given index.
-The database must be sorted by the index and mmap-able.
+The database must be sorted by the index. If it cannot be mmapped as a whole,
+it is mapped a window at a time, read into memory, or searched with positional reads instead.
 
     # database must be sorted by the index, which is the first column by default
     $ cat database
@@ -33,266 +43,2154 @@ The database must be sorted by the index and mmap-able.
     $ bsq database -w 19
     19	nineteen
     19	another nineteen
+
+    # set `--nearest` to print the neighboring records if nothing matches
+    $ bsq database -w --nearest both 20
+    192	one hundred ninety two
+    24	twenty four
+
+    # set `-g` to query with a glob pattern; the part before the first wildcard is binary-searched
+    $ bsq database -g '1?'
+    19	nineteen
+    19	another nineteen
+
+    # set `-r` if the database is sorted in descending order; `-` reads the database from stdin
+    $ tac database | bsq -r - 2
+    24	twenty four
+
+    # set `-b` and/or `-n` to prefix matches with the byte offset and/or line number
+    $ bsq database -bn 24
+    65	5	24	twenty four
+
+    # set `--join` to look up a sorted list of queries in a single pass, like join(1)
+    $ printf '19\\n3\\n' | bsq database -w --cut-key --join -
+    19	nineteen
+    19	another nineteen
+    3	three
+
+    # set `--max-lines` and/or `--max-bytes` to guard against a short query matching too much
+    $ bsq database --max-lines 2 1
+    `1` matches more than the limit; narrow the query or set `--truncate`
+
+    # set `--willneed` to read ahead a database on a slow filesystem, e.g., NFS, for a batch
+    $ bsq --willneed -w database < queries
+
+    # set `--separate` to tell apart the results of each query read from stdin by `--`,
+    # or `--group-separator STR` to do so by STR
+    $ printf '19\\n3\\n' | bsq database -w --separate
+    19	nineteen
+    19	another nineteen
+    --
+    3	three
+
+    # set `--json` to print JSON Lines; field names are taken from the header with `-H`
+    $ bsq database --json -w 3
+    {\"query\":\"3\",\"start\":80,\"end\":87,\"fields\":{\"f1\":\"3\",\"f2\":\"three\"}}
+
+    # set `-e` to test for existence with the exit status, as in `grep -q`
+    $ bsq database -e -w 2 || echo missing
+    missing
+
+    # search several databases, e.g., daily shards, with `--db`
+    $ bsq --source db-2024-01 -w 3 --db db-2024-02 db-2024-03
+    db-2024-01	3	three
+    db-2024-03	3	drei
 "
 )]
 struct Arguments {
-    /// field delimiter
-    #[arg(short, default_value_t = '\t')]
-    delimiter: char,
+    /// field delimiter; may be more than one character
+    #[arg(short, default_value_t = String::from("\t"))]
+    delimiter: String,
     /// match the entire index, as opposed to prefix-match
     #[arg(short = 'w', default_value_t = false)]
     exact_match: bool,
     /// specify the index field
     #[arg(short = 'f', default_value_t = 1)]
     index_field: usize,
-    /// Database file; must be sorted by the key and mmap-able
+    /// compare keys case-insensitively, as in `sort -f`
+    #[arg(long, default_value_t = false)]
+    fold_case: bool,
+    /// match keys containing the query by scanning the whole database, in O(n) time
+    #[arg(long, conflicts_with_all = ["exact_match", "scan_suffix", "nearest", "join"])]
+    scan_substring: bool,
+    /// match keys ending with the query by scanning the whole database, in O(n) time
+    #[arg(long, conflicts_with_all = ["exact_match", "nearest", "join"])]
+    scan_suffix: bool,
+    /// if nothing matches, suggest nearby keys within the given edit distance
+    #[arg(long, value_name = "N")]
+    fuzzy: Option<usize>,
+    /// interpret queries as glob patterns, e.g., `2024-06-*`, matching the entire index
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["exact_match", "scan_substring", "scan_suffix", "nearest", "join"]
+    )]
+    glob: bool,
+    /// the order the database is sorted in: bytes (`LC_ALL=C sort`), numeric (`sort -n`),
+    /// version (`sort -V`), or locale=NAME (`LC_ALL=NAME sort`) if the locale is installed
+    #[arg(long, default_value = "bytes", value_parser = parse_order)]
+    order: Order,
+    /// the database is sorted in descending order
+    #[arg(short = 'r', default_value_t = false)]
+    descending: bool,
+    /// ignore leading and trailing blanks of keys
+    #[arg(long, default_value_t = false)]
+    trim: bool,
+    /// if nothing matches, print the nearest record(s) instead
+    #[arg(long, value_enum)]
+    nearest: Option<Nearest>,
+    /// the first line of the database is a header, which is not searched
+    #[arg(short = 'H', long, default_value_t = false)]
+    header: bool,
+    /// print each match as a JSON object, keyed by the header or f1..fN
+    #[arg(long, default_value_t = false)]
+    json: bool,
+    /// print the summary of each query to stderr; repeat to trace every probe
+    #[arg(short, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// suppress warnings
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+    /// print a line of `--` between the results of queries
+    #[arg(long, default_value_t = false, conflicts_with = "json")]
+    separate: bool,
+    /// print a line of STR between the results of queries, instead of `--` with `--separate`
+    #[arg(long, value_name = "STR", conflicts_with = "json")]
+    group_separator: Option<String>,
+    /// abort if a query would print more than N bytes
+    #[arg(long, value_name = "N")]
+    max_bytes: Option<usize>,
+    /// abort if a query would print more than N lines
+    #[arg(long, value_name = "N")]
+    max_lines: Option<usize>,
+    /// print up to `--max-bytes` or `--max-lines` with a warning, instead of aborting
+    #[arg(long, default_value_t = false)]
+    truncate: bool,
+    /// map at most BYTES of the database at a time, e.g., to reduce the memory pressure
+    /// of many concurrent processes
+    #[arg(long, value_name = "BYTES")]
+    mmap_window: Option<usize>,
+    /// directory to spool a piped database into; defaults to $TMPDIR or /tmp
+    #[arg(long, value_name = "DIR")]
+    tmpdir: Option<PathBuf>,
+    /// remember the results of up to N recent queries, for batches with repeated queries
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    cache_size: usize,
+    /// advise the kernel to read ahead the whole database, e.g., for `--join`
+    #[arg(long, default_value_t = false, conflicts_with = "random")]
+    willneed: bool,
+    /// advise the kernel against reading ahead, e.g., for a few point lookups
+    #[arg(long, default_value_t = false)]
+    random: bool,
+    /// print nothing; exit with 0 if any query matches, 1 if none does, as in `grep -q`
+    #[arg(short, default_value_t = false)]
+    exists: bool,
+    /// additional database files, searched in the given order after DATABASE
+    #[arg(long = "db", num_args = 1..)]
+    more_databases: Vec<String>,
+    /// remove the index field from the output
+    #[arg(long, default_value_t = false)]
+    cut_key: bool,
+    /// look up every line of the sorted query file in one pass, prefixing matches with the query
+    #[arg(long, value_name = "QUERY_FILE", conflicts_with = "query")]
+    join: Option<String>,
+    /// prefix each match with its byte offset in the database
+    #[arg(short = 'b', default_value_t = false)]
+    byte_offset: bool,
+    /// prefix each match with its line number in the database
+    #[arg(short = 'n', default_value_t = false)]
+    line_number: bool,
+    /// prefix each match with the path of the database it was found in
+    #[arg(long, default_value_t = false)]
+    source: bool,
+    /// Database file; must be sorted by the key. If `-` or a pipe, it is spooled to a temporary file
     database: String,
     /// query; If omitted, read from stdin line by line
     query: Option<String>,
 }
 
+#[allow(clippy::enum_variant_names)]
 enum MatchType {
     ExactMatch,
     PrefixMatch,
+    // the following require a linear scan
+    SubstringMatch,
+    SuffixMatch,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Nearest {
+    /// the greatest key less than the query
+    Floor,
+    /// the smallest key greater than the query
+    Ceil,
+    /// both floor and ceil
+    Both,
+}
+
+#[derive(Clone, Copy)]
+enum Advice {
+    WillNeed,
+    Random,
+}
+
+#[derive(Clone)]
+enum Order {
+    Bytes,
+    Numeric,
+    Version,
+    // the collation of the named locale, once set by `set_collation`
+    Locale(String),
+}
+
+fn parse_order(s: &str) -> Result<Order, String> {
+    match s {
+        "bytes" => Ok(Order::Bytes),
+        "numeric" => Ok(Order::Numeric),
+        "version" => Ok(Order::Version),
+        "locale=C" | "locale=POSIX" => Ok(Order::Bytes),
+        _ if s.starts_with("locale=") => Ok(Order::Locale(s["locale=".len()..].to_owned())),
+        _ => Err("expected one of bytes, numeric, version, locale=NAME".to_owned()),
+    }
+}
+
+// collate by the locale from now on, as `sort` would
+fn set_collation(locale: &str) -> Result<(), String> {
+    let c_locale = CString::new(locale).map_err(|_| "the locale contains NUL".to_owned())?;
+    let result = unsafe { libc::setlocale(libc::LC_COLLATE, c_locale.as_ptr()) };
+    match result.is_null() {
+        true => Err(format!(
+            "locale `{}` is not available; see `locale -a`",
+            locale
+        )),
+        false => Ok(()),
+    }
+}
+
+// leading number of the key as in `sort -n`; 0 if there is none
+fn parse_numeric(key: &[u8]) -> f64 {
+    let key = key.trim_ascii_start();
+    let mut end = match key.first() {
+        Some(b'-') => 1,
+        _ => 0,
+    };
+    while end < key.len() && key[end].is_ascii_digit() {
+        end += 1;
+    }
+    if end < key.len() && key[end] == b'.' {
+        end += 1;
+        while end < key.len() && key[end].is_ascii_digit() {
+            end += 1;
+        }
+    }
+    std::str::from_utf8(&key[..end])
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0.0)
+}
+
+fn numeric_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    parse_numeric(a).total_cmp(&parse_numeric(b))
+}
+
+// as in `sort -V`: digit runs compare numerically,
+// and otherwise `~` < end of string < letters < other characters
+fn version_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let weight = |c: Option<&u8>| match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => *c as i32,
+        Some(b'~') => -1,
+        Some(c) => *c as i32 + 256,
+    };
+    let is_digit = |c: Option<&u8>| c.is_some_and(|c| c.is_ascii_digit());
+
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let (x, y) = (weight(a.get(i)), weight(b.get(j)));
+            if x != y {
+                return x.cmp(&y);
+            }
+            i += 1;
+            j += 1;
+        }
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a.get(i)) && is_digit(b.get(j)) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if is_digit(a.get(i)) {
+            return Ordering::Greater;
+        }
+        if is_digit(b.get(j)) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+// strcoll of libc, with ties broken by bytes as in `sort`; the keys end at NUL if any
+fn locale_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let c_string = |x: &[u8]| {
+        let end = x.iter().position(|&b| b == 0).unwrap_or(x.len());
+        CString::new(&x[..end]).unwrap()
+    };
+    let result = unsafe { libc::strcoll(c_string(a).as_ptr(), c_string(b).as_ptr()) };
+    result.cmp(&0).then_with(|| a.cmp(b))
+}
+
+#[test]
+fn test_order() {
+    assert_eq!(numeric_cmp(b"9", b"10"), Ordering::Less);
+    assert_eq!(numeric_cmp(b" -1.5x", b"-1"), Ordering::Less);
+    assert_eq!(numeric_cmp(b"abc", b"0"), Ordering::Equal);
+    assert_eq!(version_cmp(b"1.2", b"1.10"), Ordering::Less);
+    assert_eq!(version_cmp(b"1.0~rc1", b"1.0"), Ordering::Less);
+    assert_eq!(version_cmp(b"1.02", b"1.2"), Ordering::Equal);
+    assert_eq!(version_cmp(b"a10", b"a9b"), Ordering::Greater);
+    // the C collation is the byte order
+    assert_eq!(locale_cmp(b"B", b"a"), Ordering::Less);
+    assert_eq!(locale_cmp(b"a", b"a-c"), Ordering::Less);
+    assert!(matches!(parse_order("locale=POSIX"), Ok(Order::Bytes)));
+    assert!(
+        matches!(parse_order("locale=en_US.UTF-8"), Ok(Order::Locale(x)) if x == "en_US.UTF-8")
+    );
+    assert!(set_collation("no_SUCH.locale").is_err());
+}
+
+#[test]
+fn test_lower_bound_numeric() {
+    let key_option = KeyOption {
+        order: Order::Numeric,
+        ..KeyOption::new(b" ", 0)
+    };
+    let database = "1 a\n3 b\n10 c\n10 d\n200 e\n".as_bytes();
+    assert_eq!(lower_bound(b"10", database, &key_option), 8);
+    assert_eq!(lower_bound(b"4", database, &key_option), 8);
+    assert_eq!(
+        find_matches(database, 8, b"10.0", &key_option, &MatchType::ExactMatch),
+        Some((8, 18))
+    );
+}
+
+// how to locate and normalize the key of each record
+struct KeyOption {
+    delim: Vec<u8>,
+    key_idx: usize, // 0-index
+    fold_case: bool,
+    trim: bool,
+    descending: bool,
+    order: Order,
+}
+
+impl KeyOption {
+    fn new(delim: &[u8], key_idx: usize) -> Self {
+        Self {
+            delim: delim.to_vec(),
+            key_idx,
+            fold_case: false,
+            trim: false,
+            descending: false,
+            order: Order::Bytes,
+        }
+    }
+
+    // locate the key field within the record
+    fn key_range(&self, record: &[u8]) -> Option<(usize, usize)> {
+        let record = strip_cr(record);
+        let mut key_start = 0;
+        for _ in 0..self.key_idx {
+            key_start += find(&record[key_start..], &self.delim)? + self.delim.len();
+        }
+        let key_end = match find(&record[key_start..], &self.delim) {
+            Some(pos) => key_start + pos,
+            None => record.len(),
+        };
+        Some((key_start, key_end))
+    }
+
+    // the record without the key field and its delimiter
+    fn cut_key<'a>(&self, record: &'a [u8]) -> Cow<'a, [u8]> {
+        let record = strip_cr(record);
+        let (key_start, key_end) = match self.key_range(record) {
+            Some(range) => range,
+            None => return Cow::Borrowed(record),
+        };
+        let (cut_start, cut_end) = match (key_start, key_end < record.len()) {
+            (0, true) => (0, key_end + self.delim.len()),
+            (0, false) => (0, key_end),
+            _ => (key_start - self.delim.len(), key_end),
+        };
+        Cow::Owned([&record[..cut_start], &record[cut_end..]].concat())
+    }
+
+    // compare normalized keys in the order of the database
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ordering = match self.order {
+            Order::Bytes => a.cmp(b),
+            Order::Numeric => numeric_cmp(a, b),
+            Order::Version => version_cmp(a, b),
+            Order::Locale(_) => locale_cmp(a, b),
+        };
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+
+    // whether a record with the key comes before all matches of the query
+    fn precedes(&self, key: &[u8], query: &[u8], match_type: &MatchType) -> bool {
+        match (self.descending, match_type) {
+            // longer keys with the query as the prefix come before the query itself
+            (true, MatchType::PrefixMatch) => {
+                self.compare(key, query) == Ordering::Less && !key.starts_with(query)
+            }
+            _ => self.compare(key, query) == Ordering::Less,
+        }
+    }
+
+    // whether the normalized key matches the query
+    fn matches(&self, key: &[u8], query: &[u8], match_type: &MatchType) -> bool {
+        match match_type {
+            MatchType::ExactMatch => self.compare(key, query) == Ordering::Equal,
+            MatchType::PrefixMatch => key.starts_with(query),
+            MatchType::SubstringMatch => find(key, query).is_some(),
+            MatchType::SuffixMatch => key.ends_with(query),
+        }
+    }
+
+    // the database must have been sorted with the same normalization
+    fn normalize<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        let key = match self.trim {
+            true => key.trim_ascii(),
+            false => key,
+        };
+        match self.fold_case {
+            true => Cow::Owned(key.to_ascii_uppercase()),
+            false => Cow::Borrowed(key),
+        }
+    }
 }
 
 struct ProgramOption {
-    delim: u8,
+    key_option: KeyOption,
     match_type: MatchType,
-    key_idx: usize, // 0-index
-    database: String,
+    glob: bool,
+    fuzzy: Option<usize>,
+    nearest: Option<Nearest>,
+    header: bool,
+    json: bool,
+    source: bool,
+    cut_key: bool,
+    byte_offset: bool,
+    line_number: bool,
+    exists: bool,
+    group_separator: Option<String>,
+    advice: Option<Advice>,
+    mmap_window: Option<usize>,
+    tmpdir: PathBuf,
+    cache_size: usize,
+    max_bytes: Option<usize>,
+    max_lines: Option<usize>,
+    truncate: bool,
+    join: Option<String>,
+    databases: Vec<String>,
     query: Option<String>,
 }
 
 fn parse_arguments() -> Result<ProgramOption, String> {
     let args = Arguments::parse();
+    VERBOSITY.store(
+        match args.quiet {
+            true => 0,
+            false => (WARN + args.verbose).min(TRACE),
+        },
+        AtomicOrdering::Relaxed,
+    );
     if args.index_field == 0 {
         return Err("index field must be positive".to_owned());
     }
+    if args.delimiter.is_empty() {
+        return Err("delimiter must not be empty".to_owned());
+    }
+    let scan = args.scan_substring || args.scan_suffix;
+    if scan && verbosity() >= WARN {
+        eprintln!("warning: scanning the entire database for every query; this takes O(n) time");
+    }
+    if args.glob && !matches!(args.order, Order::Bytes) {
+        return Err("glob match requires `--order bytes`".to_owned());
+    }
+    if !args.exact_match && !scan && !args.glob && !matches!(args.order, Order::Bytes) {
+        return Err("prefix match requires `--order bytes`; set `-w`".to_owned());
+    }
+    let stdin_database = args.database == "-" || args.more_databases.iter().any(|x| x == "-");
+    let stdin_queries = match (&args.query, &args.join) {
+        (None, None) => true,
+        (None, Some(path)) => path == "-",
+        _ => false,
+    };
+    if stdin_database && stdin_queries {
+        return Err("cannot read both the database and the queries from stdin".to_owned());
+    }
+    if let Order::Locale(locale) = &args.order {
+        set_collation(locale)?;
+    }
 
     Ok(ProgramOption {
-        key_idx: args.index_field - 1, // 0-index
-        match_type: match args.exact_match {
-            true => MatchType::ExactMatch,
-            false => MatchType::PrefixMatch,
+        key_option: KeyOption {
+            fold_case: args.fold_case,
+            trim: args.trim,
+            descending: args.descending,
+            order: args.order,
+            ..KeyOption::new(
+                args.delimiter.as_bytes(),
+                args.index_field - 1, // 0-index
+            )
+        },
+        match_type: match (args.exact_match, args.scan_substring, args.scan_suffix) {
+            (true, _, _) => MatchType::ExactMatch,
+            (_, true, _) => MatchType::SubstringMatch,
+            (_, _, true) => MatchType::SuffixMatch,
+            _ => MatchType::PrefixMatch,
+        },
+        glob: args.glob,
+        fuzzy: args.fuzzy,
+        nearest: args.nearest,
+        header: args.header,
+        json: args.json,
+        source: args.source,
+        cut_key: args.cut_key,
+        byte_offset: args.byte_offset,
+        line_number: args.line_number,
+        exists: args.exists,
+        group_separator: args
+            .group_separator
+            .or_else(|| args.separate.then(|| "--".to_owned())),
+        advice: match (args.willneed, args.random) {
+            (true, _) => Some(Advice::WillNeed),
+            (_, true) => Some(Advice::Random),
+            _ => None,
         },
-        database: args.database,
+        mmap_window: args.mmap_window,
+        tmpdir: args.tmpdir.unwrap_or_else(std::env::temp_dir),
+        cache_size: args.cache_size,
+        max_bytes: args.max_bytes,
+        max_lines: args.max_lines,
+        truncate: args.truncate,
+        join: args.join,
+        databases: std::iter::once(args.database)
+            .chain(args.more_databases)
+            .collect(),
         query: args.query,
-        delim: args.delimiter.to_string().as_bytes()[0],
     })
 }
 
-// if n == 0, returns None
-// else calls position n time and returns the final value
-fn nth_pos<T>(mut it: impl Iterator<Item = T>, item: T, n: usize) -> Option<usize>
-where
-    T: std::cmp::PartialEq,
-{
-    let mut result = 0;
-    for _ in 0..n {
-        result += it.position(|x| x == item)?;
+// levels of diagnostic messages to stderr
+const WARN: u8 = 1;
+const INFO: u8 = 2;
+const TRACE: u8 = 3;
+static VERBOSITY: AtomicU8 = AtomicU8::new(WARN);
+
+fn verbosity() -> u8 {
+    VERBOSITY.load(AtomicOrdering::Relaxed)
+}
+
+// granularity of scanning for record boundaries
+const CHUNK_SIZE: usize = 4 << 10;
+// granularity of writing out matched ranges
+const WRITE_SIZE: usize = 1 << 20;
+
+// position of the first occurrence of needle in haystack
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
     }
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
 
-    Some(result + n - 1)
+// split the record by a possibly multi-byte delimiter
+fn split<'a>(mut record: &'a [u8], delim: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        match find(record, delim) {
+            Some(pos) => {
+                let field = &record[..pos];
+                record = &record[pos + delim.len()..];
+                Some(field)
+            }
+            None => {
+                done = true;
+                Some(record)
+            }
+        }
+    })
 }
 
-// find the first position where the match can be inserted into
-fn lower_bound(key: &str, database: &[u8], delim: u8, key_idx: usize) -> usize {
-    let mut lb = 0usize;
-    let mut ub = database.len();
-    loop {
-        let mid = (lb + ub) / 2;
-        let start = match database[0..mid].iter().rev().position(|&x| x == b'\n') {
-            Some(pos) => mid - pos,
-            None => 0,
-        };
-        let end = match database[start..].iter().position(|&x| x == b'\n') {
-            Some(pos) => start + pos,
-            None => ub,
-        };
+#[test]
+fn test_cut_key() {
+    assert_eq!(KeyOption::new(b"||", 0).cut_key(b"a||b||c"), &b"b||c"[..]);
+    assert_eq!(KeyOption::new(b"||", 1).cut_key(b"a||b||c"), &b"a||c"[..]);
+    assert_eq!(KeyOption::new(b"||", 2).cut_key(b"a||b||c"), &b"a||b"[..]);
+    assert_eq!(KeyOption::new(b"||", 0).cut_key(b"a"), &b""[..]);
+    assert_eq!(KeyOption::new(b"||", 2).cut_key(b"a||b||c\r"), &b"a||b"[..]);
+    assert_eq!(
+        KeyOption::new(b"||", 3).cut_key(b"a||b||c"),
+        &b"a||b||c"[..]
+    );
+}
 
-        eprintln!("{}", std::str::from_utf8(&database[start..end]).expect(""));
-        let key_start = match key_idx {
-            0 => start,
-            _ => match nth_pos(database[start..end].iter(), &delim, key_idx) {
-                Some(pos) => start + pos + 1,
-                None => end,
-            },
+#[test]
+fn test_split() {
+    let fields: Vec<&[u8]> = split(b"a||b|c||", b"||").collect();
+    assert_eq!(fields, vec![&b"a"[..], b"b|c", b""]);
+    let key_option = KeyOption::new("§".as_bytes(), 1);
+    assert_eq!(key_option.key_range("1§ab§c".as_bytes()), Some((3, 5)));
+    assert_eq!(key_option.key_range(b"1ab"), None);
+    assert_eq!(KeyOption::new(b"\t", 1).key_range(b"a\tb\r"), Some((2, 3)));
+}
+
+// random access to the bytes of a database
+trait Source {
+    fn len(&self) -> usize;
+    // bytes in [start, end)
+    fn read(&self, start: usize, end: usize) -> Cow<'_, [u8]>;
+    // hint how the whole source will be accessed; only meaningful for mmap
+    fn advise(&self, _advice: Advice) {}
+    // fault in the pages of [start, end) ahead of reading them; only meaningful for mmap
+    fn prefetch(&self, _start: usize, _end: usize) {}
+}
+
+impl Source for [u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self[start..end])
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+impl Source for Mmap {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self[start..end])
+    }
+
+    fn advise(&self, advice: Advice) {
+        let advice = match advice {
+            Advice::WillNeed => libc::MADV_WILLNEED,
+            Advice::Random => libc::MADV_RANDOM,
         };
+        // just a hint; failure is harmless
+        unsafe { libc::madvise(self.as_ptr() as *mut libc::c_void, self.len(), advice) };
+    }
 
-        let key_end = match database[key_start..end].iter().position(|&x| x == delim) {
-            Some(pos) => key_start + pos,
-            None => end,
+    fn prefetch(&self, start: usize, end: usize) {
+        let page_size = page_size();
+        let begin = start / page_size * page_size; // madvise requires page alignment
+        if begin >= end {
+            return;
+        }
+        unsafe {
+            libc::madvise(
+                self.as_ptr().add(begin) as *mut libc::c_void,
+                end - begin,
+                libc::MADV_WILLNEED,
+            )
         };
+        // touch every page so that the faults happen before writing rather than during
+        for pos in (begin..end).step_by(page_size) {
+            unsafe { std::ptr::read_volatile(self.as_ptr().add(pos)) };
+        }
+    }
+}
 
-        eprintln!(
-            "{}\t{}",
-            key,
-            std::str::from_utf8(&database[key_start..key_end]).unwrap()
-        );
-        match key.as_bytes().cmp(&database[key_start..key_end]) {
-            Ordering::Less | Ordering::Equal => match start {
-                0 => {
-                    return 0;
-                }
-                _ => {
-                    ub = start - 1;
-                }
-            },
-            Ordering::Greater => {
-                lb = end;
+impl Source for Vec<u8> {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self[start..end])
+    }
+}
+
+// positional reads with a single cached block, for large files that cannot be mmapped
+struct FileSource {
+    file: File,
+    len: usize,
+    block: RefCell<(usize, Vec<u8>)>, // (offset, data)
+}
+
+impl FileSource {
+    const BLOCK_SIZE: usize = 64 << 10;
+
+    fn new(file: File, len: usize) -> Self {
+        Self {
+            file,
+            len,
+            block: RefCell::new((0, Vec::new())),
+        }
+    }
+}
+
+impl Source for FileSource {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        if start == end {
+            return Cow::Borrowed(&[]);
+        }
+        let offset = start / Self::BLOCK_SIZE * Self::BLOCK_SIZE;
+        if end > offset + Self::BLOCK_SIZE {
+            let mut buf = vec![0; end - start];
+            self.file
+                .read_exact_at(&mut buf, start as u64)
+                .expect("error reading database");
+            return Cow::Owned(buf);
+        }
+
+        let mut block = self.block.borrow_mut();
+        if block.1.is_empty() || block.0 != offset {
+            block.1.resize((self.len - offset).min(Self::BLOCK_SIZE), 0);
+            self.file
+                .read_exact_at(&mut block.1, offset as u64)
+                .expect("error reading database");
+            block.0 = offset;
+        }
+        Cow::Owned(block.1[start - offset..end - offset].to_vec())
+    }
+}
+
+// the record without the `\r` of a `\r\n` terminator
+fn strip_cr(record: &[u8]) -> &[u8] {
+    record.strip_suffix(b"\r").unwrap_or(record)
+}
+
+// maps a window of the file at a time, copying out the bytes read,
+// for files larger than the address space or to limit the mapped memory
+struct WindowSource {
+    file: File,
+    len: usize,
+    size: usize,
+    window: RefCell<(usize, Mmap)>, // (offset, mapping)
+}
+
+impl WindowSource {
+    fn new(file: &File, len: usize, size: usize) -> std::io::Result<Self> {
+        let size = size.max(1).div_ceil(page_size()) * page_size();
+        let file = file.try_clone()?;
+        let mmap = unsafe { MmapOptions::new().len(size.min(len)).map(&file)? };
+        Ok(Self {
+            file,
+            len,
+            size,
+            window: RefCell::new((0, mmap)),
+        })
+    }
+}
+
+impl Source for WindowSource {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        if start == end {
+            return Cow::Borrowed(&[]);
+        }
+        let mut window = self.window.borrow_mut();
+        if start < window.0 || end > window.0 + window.1.len() {
+            // slide the window so that it begins at the page of start
+            let offset = start / page_size() * page_size();
+            let len = self.size.max(end - offset).min(self.len - offset);
+            let mmap = unsafe {
+                MmapOptions::new()
+                    .offset(offset as u64)
+                    .len(len)
+                    .map(&self.file)
             }
+            .expect("error mapping database");
+            *window = (offset, mmap);
         }
+        Cow::Owned(window.1[start - window.0..end - window.0].to_vec())
+    }
+}
+
+// start of the record that contains database[pos]
+fn record_start<S: Source + ?Sized>(database: &S, pos: usize) -> usize {
+    let mut end = pos;
+    while end > 0 {
+        let begin = end.saturating_sub(CHUNK_SIZE);
+        if let Some(offset) = database.read(begin, end).iter().rposition(|&x| x == b'\n') {
+            return begin + offset + 1;
+        }
+        end = begin;
+    }
+    0
+}
+
+// end of the record that begins at start, excluding the newline
+fn record_end<S: Source + ?Sized>(database: &S, start: usize) -> usize {
+    let mut begin = start;
+    while begin < database.len() {
+        let end = (begin + CHUNK_SIZE).min(database.len());
+        if let Some(offset) = database.read(begin, end).iter().position(|&x| x == b'\n') {
+            return begin + offset;
+        }
+        begin = end;
+    }
+    database.len()
+}
+
+#[test]
+fn test_file_source() {
+    let path = std::env::temp_dir().join(format!("bsq-test-{}", std::process::id()));
+    let data: Vec<u8> = (0..FileSource::BLOCK_SIZE * 2 + 100)
+        .map(|i| match i % 7 {
+            6 => b'\n',
+            x => b'0' + x as u8,
+        })
+        .collect();
+    std::fs::write(&path, &data).unwrap();
+    let source = FileSource::new(File::open(&path).unwrap(), data.len());
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(source.read(3, 10), data.read(3, 10));
+    let last = FileSource::BLOCK_SIZE * 2 + 99;
+    assert_eq!(source.read(10, last), data.read(10, last));
+    assert_eq!(record_start(&source, last), record_start(&data, last));
+    assert_eq!(record_end(&source, 70000), record_end(&data, 70000));
+}
+
+#[test]
+fn test_window_source() {
+    let path = std::env::temp_dir().join(format!("bsq-test-window-{}", std::process::id()));
+    let data: Vec<u8> = (0..page_size() * 5 + 100)
+        .map(|i| match i % 7 {
+            6 => b'\n',
+            x => b'0' + x as u8,
+        })
+        .collect();
+    std::fs::write(&path, &data).unwrap();
+    let source = WindowSource::new(&File::open(&path).unwrap(), data.len(), page_size()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let last = data.len() - 1;
+    assert_eq!(source.read(3, 10), data.read(3, 10));
+    assert_eq!(source.read(last - 10, last), data.read(last - 10, last));
+    assert_eq!(source.read(10, last), data.read(10, last));
+    assert_eq!(record_start(&source, last), record_start(&data, last));
+    assert_eq!(record_end(&source, 100), record_end(&data, 100));
+}
+
+// find the first position where pred does not hold for the normalized key,
+// given that pred holds for all records before it and none after it
+fn partition_point<S: Source + ?Sized>(
+    database: &S,
+    key_option: &KeyOption,
+    pred: impl Fn(&[u8]) -> bool,
+) -> usize {
+    partition_range(database, 0, database.len(), key_option, pred)
+}
+
+// partition_point within database[lb..ub], where lb is the start of a record
+fn partition_range<S: Source + ?Sized>(
+    database: &S,
+    mut lb: usize,
+    mut ub: usize,
+    key_option: &KeyOption,
+    pred: impl Fn(&[u8]) -> bool,
+) -> usize {
+    // records before lb satisfy pred; records from ub do not
+    while lb < ub {
+        let mid = (lb + ub) / 2;
+        let start = record_start(database, mid);
+        let end = record_end(database, start);
+        let record = database.read(start, end);
+
+        let (key_start, key_end) = key_option
+            .key_range(&record)
+            .unwrap_or((record.len(), record.len()));
+
+        if verbosity() >= TRACE {
+            eprintln!(
+                "probe [{}, {}): {}",
+                start,
+                end,
+                String::from_utf8_lossy(&record[key_start..key_end])
+            );
+        }
+        match pred(&key_option.normalize(&record[key_start..key_end])) {
+            false => {
+                ub = start;
+            }
+            true => {
+                lb = end + 1;
+            }
+        }
+    }
+    ub
+}
 
-        if lb >= ub {
-            return ub + 1;
+// partition_point at or after from, the start of a record,
+// probing with exponentially growing steps so that nearby answers are found quickly
+fn gallop<S: Source + ?Sized>(
+    database: &S,
+    from: usize,
+    key_option: &KeyOption,
+    pred: impl Fn(&[u8]) -> bool,
+) -> usize {
+    let mut lb = from;
+    let mut step = 1;
+    while lb + step < database.len() {
+        let start = record_start(database, lb + step);
+        if !pred(&key_at(database, start, key_option)) {
+            return partition_range(database, lb, start, key_option, pred);
         }
+        lb = record_end(database, start) + 1;
+        step *= 2;
+    }
+    partition_range(database, lb, database.len(), key_option, pred)
+}
+
+// find the first position where the match can be inserted into
+// `key` must be already normalized
+fn lower_bound<S: Source + ?Sized>(key: &[u8], database: &S, key_option: &KeyOption) -> usize {
+    partition_point(database, key_option, |x| {
+        key_option.compare(x, key) == Ordering::Less
+    })
+}
+
+// find the first position where the matches of the query would be
+fn match_start<S: Source + ?Sized>(
+    query: &[u8],
+    database: &S,
+    key_option: &KeyOption,
+    match_type: &MatchType,
+) -> usize {
+    partition_point(database, key_option, |x| {
+        key_option.precedes(x, query, match_type)
+    })
+}
+
+#[test]
+fn test_gallop() {
+    let key_option = KeyOption::new(b" ", 0);
+    let database = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n".as_bytes();
+    for (from, query) in [(0, b"a"), (0, b"f"), (4, b"c"), (4, b"j"), (10, b"z")] {
+        let pred = |x: &[u8]| key_option.precedes(x, query, &MatchType::ExactMatch);
+        assert_eq!(
+            gallop(database, from, &key_option, pred),
+            lower_bound(query, database, &key_option).max(from)
+        );
     }
 }
 
 #[test]
 fn test_lower_bound1() {
-    let delim = b' ';
+    let key_option = KeyOption::new(b" ", 0);
     let database = "a\nab\nabc\nabcd\nabe".as_bytes();
-    assert_eq!(lower_bound("a", database, delim, 0), 0);
-    assert_eq!(lower_bound("ab", database, delim, 0), 2);
-    assert_eq!(lower_bound("abc", database, delim, 0), 5);
-    assert_eq!(lower_bound("abcd", database, delim, 0), 9);
-    assert_eq!(lower_bound("abe", database, delim, 0), 14);
+    assert_eq!(lower_bound(b"a", database, &key_option), 0);
+    assert_eq!(lower_bound(b"ab", database, &key_option), 2);
+    assert_eq!(lower_bound(b"abc", database, &key_option), 5);
+    assert_eq!(lower_bound(b"abcd", database, &key_option), 9);
+    assert_eq!(lower_bound(b"abe", database, &key_option), 14);
 }
 
 #[test]
 fn test_lower_bound2() {
-    let delim = b' ';
+    let key_option = KeyOption::new(b" ", 1);
     let database = "0 a\n1 ab\n2 abc\n3 abcd\n4 abe".as_bytes();
-    assert_eq!(lower_bound("a", database, delim, 1), 0);
-    assert_eq!(lower_bound("ab", database, delim, 1), 4);
-    assert_eq!(lower_bound("abc", database, delim, 1), 9);
-    assert_eq!(lower_bound("abcd", database, delim, 1), 15);
-    assert_eq!(lower_bound("abe", database, delim, 1), 22);
+    assert_eq!(lower_bound(b"a", database, &key_option), 0);
+    assert_eq!(lower_bound(b"ab", database, &key_option), 4);
+    assert_eq!(lower_bound(b"abc", database, &key_option), 9);
+    assert_eq!(lower_bound(b"abcd", database, &key_option), 15);
+    assert_eq!(lower_bound(b"abe", database, &key_option), 22);
 }
 
 #[test]
 fn test_lower_bound3() {
-    let delim = b' ';
+    let key_option = KeyOption::new(b" ", 2);
     let database = "0 x a\n1 y ab\n2 z abc\n3 w abcd\n4 u abe".as_bytes();
-    assert_eq!(lower_bound("a", database, delim, 2), 0);
-    assert_eq!(lower_bound("ab", database, delim, 2), 6);
-    assert_eq!(lower_bound("abc", database, delim, 2), 13);
-    assert_eq!(lower_bound("abcd", database, delim, 2), 21);
-    assert_eq!(lower_bound("abe", database, delim, 2), 30);
+    assert_eq!(lower_bound(b"a", database, &key_option), 0);
+    assert_eq!(lower_bound(b"ab", database, &key_option), 6);
+    assert_eq!(lower_bound(b"abc", database, &key_option), 13);
+    assert_eq!(lower_bound(b"abcd", database, &key_option), 21);
+    assert_eq!(lower_bound(b"abe", database, &key_option), 30);
 }
 
-fn get_match_range(
-    database: &[u8],
+#[test]
+fn test_lower_bound_out_of_range() {
+    let key_option = KeyOption::new(b" ", 0);
+    let database = "b\nc\nd\n".as_bytes();
+    assert_eq!(lower_bound(b"a", database, &key_option), 0);
+    assert_eq!(lower_bound(b"e", database, &key_option), database.len());
+    assert_eq!(
+        key_at(database, record_start(database, 5), &key_option),
+        b"d"
+    );
+}
+
+#[test]
+fn test_match_start_descending() {
+    let key_option = KeyOption {
+        descending: true,
+        ..KeyOption::new(b" ", 0)
+    };
+    let database = "b\nabc\nab\nab\na\n".as_bytes();
+    assert_eq!(lower_bound(b"ab", database, &key_option), 6);
+    assert_eq!(lower_bound(b"0", database, &key_option), database.len());
+    assert_eq!(
+        match_start(b"ab", database, &key_option, &MatchType::PrefixMatch),
+        2
+    );
+    assert_eq!(lower_bound(b"aa", database, &key_option), 12);
+    assert_eq!(
+        find_nearest(database, 12, &key_option, Nearest::Both),
+        vec![(12, 14), (6, 12)]
+    );
+}
+
+#[test]
+fn test_lower_bound_normalized() {
+    let key_option = KeyOption {
+        fold_case: true,
+        trim: true,
+        ..KeyOption::new(b"\t", 0)
+    };
+    let database = "a\tx\n Ab\ty\nABC \tz\nb\tw".as_bytes();
+    let query = key_option.normalize(b"aBc");
+    assert_eq!(lower_bound(&query, database, &key_option), 10);
+    assert_eq!(
+        get_match_range(database, 10, &query, &key_option, &MatchType::ExactMatch),
+        Some((10, 17))
+    );
+}
+
+fn get_match_range<S: Source + ?Sized>(
+    database: &S,
     start: usize,
     query: &[u8],
-    key_idx: usize,
-    delim: u8,
+    key_option: &KeyOption,
     match_type: &MatchType,
 ) -> Option<(usize, usize)> {
-    let end = database.len();
-    let key_start = match key_idx {
-        0 => start,
-        _ => match nth_pos(database[start..end].iter(), &delim, key_idx) {
-            Some(pos) => start + pos + 1,
-            None => {
-                return None;
+    if start >= database.len() {
+        return None;
+    }
+    let end = record_end(database, start);
+    let record = database.read(start, end);
+    let (key_start, key_end) = key_option.key_range(&record)?;
+    let key = key_option.normalize(&record[key_start..key_end]);
+    if !key_option.matches(&key, query, match_type) {
+        return None;
+    }
+
+    Some((start, (end + 1).min(database.len())))
+}
+
+// ranges of all matching records by scanning the whole database
+fn scan_matches<S: Source + ?Sized>(
+    database: &S,
+    query: &[u8],
+    key_option: &KeyOption,
+    match_type: &MatchType,
+) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut pos = 0;
+    while pos < database.len() {
+        pos = match get_match_range(database, pos, query, key_option, match_type) {
+            Some((start, end)) => {
+                match ranges.last_mut() {
+                    Some(last) if last.1 == start => last.1 = end,
+                    _ => ranges.push((start, end)),
+                }
+                end
             }
-        },
+            None => record_end(database, pos) + 1,
+        };
+    }
+    ranges
+}
+
+#[test]
+fn test_scan_matches() {
+    let key_option = KeyOption::new(b" ", 1);
+    let database = "0 xab\n1 abx\n2 ab\n3 x\n4 b\n5".as_bytes();
+    assert_eq!(
+        scan_matches(database, b"ab", &key_option, &MatchType::SubstringMatch),
+        vec![(0, 17)]
+    );
+    assert_eq!(
+        scan_matches(database, b"b", &key_option, &MatchType::SuffixMatch),
+        vec![(0, 6), (12, 17), (21, 25)]
+    );
+}
+
+// literal prefix of the glob pattern before the first wildcard
+fn glob_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'*' | b'?' | b'[' => break,
+            b'\\' if i + 1 < pattern.len() => {
+                prefix.push(pattern[i + 1]);
+                i += 2;
+            }
+            c => {
+                prefix.push(c);
+                i += 1;
+            }
+        }
+    }
+    prefix
+}
+
+// match c against the bracket expression at pattern[p];
+// returns whether it matched and the position after the closing bracket,
+// or None if the bracket is not closed
+fn glob_class(pattern: &[u8], p: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = p + 1;
+    let negate = matches!(pattern.get(i), Some(b'!' | b'^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() && (first || pattern[i] != b']') {
+        first = false;
+        let lo = pattern[i];
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(b'-'), Some(&hi)) if hi != b']' => {
+                matched |= lo <= c && c <= hi;
+                i += 3;
+            }
+            _ => {
+                matched |= lo == c;
+                i += 1;
+            }
+        }
+    }
+    match i < pattern.len() {
+        true => Some((matched != negate, i + 1)),
+        false => None,
+    }
+}
+
+// shell-style glob match of the entire text, supporting `*`, `?`, `[...]`, and `\\` escapes
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None; // (pattern position after `*`, text position)
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match glob_class(pattern, p, text[t]) {
+                Some((true, next)) => Some(next),
+                Some((false, _)) => None,
+                None => (text[t] == b'[').then_some(p + 1),
+            },
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(&c) => (c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, t));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&x| x == b'*')
+}
+
+#[test]
+fn test_glob() {
+    assert_eq!(glob_prefix(b"2024-06-*"), b"2024-06-");
+    assert_eq!(glob_prefix(br"a\*b?c"), b"a*b");
+    assert!(glob_match(b"2024-06-*", b"2024-06-15"));
+    assert!(!glob_match(b"2024-06-*", b"2024-07-15"));
+    assert!(glob_match(b"a?c*", b"abcdef"));
+    assert!(glob_match(b"*x*y", b"axbxcy"));
+    assert!(!glob_match(b"*x*y", b"axbxcyz"));
+    assert!(glob_match(b"[a-c]1[!0-9]", b"b1x"));
+    assert!(!glob_match(b"[a-c]1[!0-9]", b"b12"));
+    assert!(glob_match(b"[]]", b"]"));
+    assert!(glob_match(br"\*", b"*"));
+    assert!(!glob_match(br"\*", b"a"));
+    assert!(glob_match(b"[ab", b"[ab"));
+}
+
+// ranges of records whose key matches the glob pattern;
+// only the literal prefix of the pattern is binary-searched
+fn find_glob_matches<S: Source + ?Sized>(
+    database: &S,
+    pattern: &[u8],
+    key_option: &KeyOption,
+) -> Vec<(usize, usize)> {
+    let literal = glob_prefix(pattern);
+    let match_type = MatchType::PrefixMatch;
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut pos = match_start(&literal, database, key_option, &match_type);
+    while let Some((start, end)) = get_match_range(database, pos, &literal, key_option, &match_type)
+    {
+        if glob_match(pattern, &key_at(database, start, key_option)) {
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        pos = end;
+    }
+    ranges
+}
+
+// range of all consecutive records matching the query from start,
+// which must form a single block as exact and prefix matches do
+fn find_matches<S: Source + ?Sized>(
+    database: &S,
+    start: usize,
+    query: &[u8],
+    key_option: &KeyOption,
+    match_type: &MatchType,
+) -> Option<(usize, usize)> {
+    let (_, first_end) = get_match_range(database, start, query, key_option, match_type)?;
+    let end = gallop(database, first_end, key_option, |x| {
+        key_option.matches(x, query, match_type)
+    });
+    Some((start, end))
+}
+
+#[test]
+fn test_find_matches() {
+    let key_option = KeyOption::new(b" ", 0);
+    let database = ["a 0\n", &"ab 1\n".repeat(100), "abc 2\n", "b 3"].concat();
+    let database = database.as_bytes();
+    let exact = |query: &[u8], start| {
+        find_matches(database, start, query, &key_option, &MatchType::ExactMatch)
+    };
+    let prefix = |query: &[u8], start| {
+        find_matches(database, start, query, &key_option, &MatchType::PrefixMatch)
     };
-    let key_end = match database[key_start..end].iter().position(|&x| x == delim) {
-        Some(pos) => key_start + pos,
-        None => end,
+    assert_eq!(exact(b"a", 0), Some((0, 4)));
+    assert_eq!(exact(b"ab", 4), Some((4, 504)));
+    assert_eq!(prefix(b"ab", 4), Some((4, 510)));
+    assert_eq!(prefix(b"a", 0), Some((0, 510)));
+    assert_eq!(prefix(b"b", 510), Some((510, 513)));
+    assert_eq!(exact(b"aa", 4), None);
+}
+
+// normalized key of the record that begins at start
+fn key_at<S: Source + ?Sized>(database: &S, start: usize, key_option: &KeyOption) -> Vec<u8> {
+    let record = database.read(start, record_end(database, start));
+    let (key_start, key_end) = key_option
+        .key_range(&record)
+        .unwrap_or((record.len(), record.len()));
+    key_option
+        .normalize(&record[key_start..key_end])
+        .into_owned()
+}
+
+// all records with the same key as the record that begins at start
+fn find_block<S: Source + ?Sized>(
+    database: &S,
+    start: usize,
+    key_option: &KeyOption,
+) -> Option<(usize, usize)> {
+    let key = key_at(database, start, key_option);
+    let first = lower_bound(&key, database, key_option);
+    find_matches(database, first, &key, key_option, &MatchType::ExactMatch)
+}
+
+// `start` is the match start of a query that has no match
+fn find_nearest<S: Source + ?Sized>(
+    database: &S,
+    start: usize,
+    key_option: &KeyOption,
+    nearest: Nearest,
+) -> Vec<(usize, usize)> {
+    let before = match start {
+        0 => None,
+        _ => find_block(database, record_start(database, start - 1), key_option),
     };
-    let is_match = match match_type {
-        MatchType::ExactMatch => query.cmp(&database[key_start..key_end]) == Ordering::Equal,
-        MatchType::PrefixMatch => database[key_start..key_end].starts_with(query),
+    let after = match start < database.len() {
+        true => find_block(database, start, key_option),
+        false => None,
     };
+    let (floor, ceil) = match key_option.descending {
+        true => (after, before),
+        false => (before, after),
+    };
+    match nearest {
+        Nearest::Floor => floor.into_iter().collect(),
+        Nearest::Ceil => ceil.into_iter().collect(),
+        Nearest::Both => floor.into_iter().chain(ceil).collect(),
+    }
+}
+
+// number of records on each side of the lower bound to look for similar keys
+const FUZZY_WINDOW: usize = 64;
 
-    if !is_match {
+// edit distance between a and b in chars, or None if it exceeds max_distance
+fn levenshtein(a: &[u8], b: &[u8], max_distance: usize) -> Option<usize> {
+    let a: Vec<char> = String::from_utf8_lossy(a).chars().collect();
+    let b: Vec<char> = String::from_utf8_lossy(b).chars().collect();
+    if a.len().abs_diff(b.len()) > max_distance {
         return None;
     }
-    let end = match database[key_end + 1..].iter().position(|&x| x == b'\n') {
-        Some(pos) => key_end + pos + 2,
-        None => end,
-    };
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = diagonal + (x != y) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+        if row.iter().min().unwrap() > &max_distance {
+            return None;
+        }
+    }
+    Some(row[b.len()]).filter(|&d| d <= max_distance)
+}
 
-    Some((start, end))
+#[test]
+fn test_levenshtein() {
+    assert_eq!(levenshtein(b"kitten", b"sitting", 3), Some(3));
+    assert_eq!(levenshtein(b"kitten", b"sitting", 2), None);
+    assert_eq!(levenshtein("caf\u{e9}".as_bytes(), b"cafe", 1), Some(1));
+    assert_eq!(levenshtein(b"", b"ab", 2), Some(2));
 }
 
-fn print_matches(
-    ofs: &mut BufWriter<File>,
-    database: &[u8],
+// distinct keys near the lower bound start within max_distance of the query,
+// ordered by the distance
+fn find_fuzzy<S: Source + ?Sized>(
+    database: &S,
     start: usize,
     query: &[u8],
-    key_idx: usize,
-    delim: u8,
-    match_type: &MatchType,
-) {
-    let mut first = start;
-    let mut last = None;
-    while let Some((_, end)) = get_match_range(database, first, query, key_idx, delim, match_type) {
-        first = end;
-        last = Some(end);
-    }
-    if let Some(end) = last {
-        ofs.write_all(&database[start..end])
+    key_option: &KeyOption,
+    max_distance: usize,
+) -> Vec<Vec<u8>> {
+    let mut keys = Vec::new();
+    let mut pos = start;
+    for _ in 0..FUZZY_WINDOW {
+        if pos == 0 {
+            break;
+        }
+        pos = record_start(database, pos - 1);
+        keys.push(key_at(database, pos, key_option));
+    }
+    pos = start;
+    for _ in 0..FUZZY_WINDOW {
+        if pos >= database.len() {
+            break;
+        }
+        keys.push(key_at(database, pos, key_option));
+        pos = record_end(database, pos) + 1;
+    }
+
+    let mut suggestions: Vec<(usize, Vec<u8>)> = keys
+        .into_iter()
+        .filter_map(|key| Some((levenshtein(query, &key, max_distance)?, key)))
+        .collect();
+    suggestions.sort();
+    suggestions.dedup();
+    suggestions.into_iter().map(|(_, key)| key).collect()
+}
+
+// copy a pipe into a temporary file that can be mmapped
+fn spool(mut input: &File, tmpdir: &Path) -> std::io::Result<File> {
+    let mut file = temp_file(tmpdir)?;
+    std::io::copy(&mut input, &mut file)?;
+    file.rewind()?;
+    Ok(file)
+}
+
+// databases smaller than this are read into memory if they cannot be mmapped
+const SLURP_LIMIT: u64 = 256 << 20;
+// size of the window mapped at a time if the whole database cannot be mmapped
+const MMAP_WINDOW: usize = 64 << 20;
+
+struct Database {
+    path: String,
+    source: Box<dyn Source>,
+    header_len: usize,                   // including the newline
+    crlf: bool,                          // records end with `\r\n`
+    field_names: Vec<String>,            // from the header, if any
+    line_cache: RefCell<(usize, usize)>, // (offset, number of newlines before offset)
+}
+
+impl Database {
+    fn open(
+        path: &str,
+        header: bool,
+        delim: &[u8],
+        window: Option<usize>,
+        tmpdir: &Path,
+    ) -> Result<Self, String> {
+        let file = File::open(match path {
+            "-" => "/dev/stdin",
+            _ => path,
+        })
+        .map_err(|e| format!("Failed to open `{}`: {}", path, e))?;
+        let mut metadata = file
+            .metadata()
+            .map_err(|e| format!("Failed to stat `{}`: {}", path, e))?;
+        let file = match metadata.file_type().is_fifo() || metadata.file_type().is_socket() {
+            true => {
+                let file = spool(&file, tmpdir)
+                    .map_err(|e| format!("Failed to spool `{}`: {}", path, e))?;
+                metadata = file
+                    .metadata()
+                    .map_err(|e| format!("Failed to stat `{}`: {}", path, e))?;
+                file
+            }
+            false => file,
+        };
+        let len = metadata.len() as usize;
+        let large = metadata.is_file() && metadata.len() > SLURP_LIMIT;
+        let mmap = match (window, metadata.len() > isize::MAX as u64) {
+            (None, false) => unsafe { MmapOptions::new().map(&file) }.ok(),
+            _ => None, // does not fit in the address space, e.g., on 32-bit targets
+        };
+        let source: Box<dyn Source> = match mmap {
+            Some(mmap) => Box::new(mmap),
+            None if metadata.is_file() && (window.is_some() || large) => {
+                match WindowSource::new(&file, len, window.unwrap_or(MMAP_WINDOW)) {
+                    Ok(window) => Box::new(window),
+                    Err(_) => Box::new(FileSource::new(file, len)),
+                }
+            }
+            // e.g., procfs, empty files, or some network filesystems
+            None => {
+                let mut data = Vec::new();
+                (&file)
+                    .read_to_end(&mut data)
+                    .map_err(|e| format!("Failed to read `{}`: {}", path, e))?;
+                Box::new(data)
+            }
+        };
+
+        let header_len = match header {
+            true => (record_end(source.as_ref(), 0) + 1).min(source.len()),
+            false => 0,
+        };
+        let header_line = source.read(0, header_len);
+        let field_names = split(
+            strip_cr(header_line.strip_suffix(b"\n").unwrap_or(&header_line)),
+            delim,
+        )
+        .filter(|_| header)
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect();
+
+        let first_end = record_end(source.as_ref(), 0);
+        let crlf = first_end > 0 && source.read(first_end - 1, first_end)[0] == b'\r';
+
+        Ok(Self {
+            path: path.to_owned(),
+            source,
+            header_len,
+            crlf,
+            field_names,
+            line_cache: RefCell::new((0, 0)),
+        })
+    }
+
+    // 1-based line number of the record at pos, counting from the previous call if possible
+    fn line_number(&self, pos: usize) -> usize {
+        let target = self.header_len + pos;
+        let mut cache = self.line_cache.borrow_mut();
+        let (mut offset, mut lines) = match cache.0 <= target {
+            true => *cache,
+            false => (0, 0),
+        };
+        while offset < target {
+            let end = (offset + WRITE_SIZE).min(target);
+            lines += self
+                .source
+                .read(offset, end)
+                .iter()
+                .filter(|&&x| x == b'\n')
+                .count();
+            offset = end;
+        }
+        *cache = (offset, lines);
+        lines + 1
+    }
+}
+
+// searchable records, i.e., excluding the header
+impl Source for Database {
+    fn len(&self) -> usize {
+        self.source.len() - self.header_len
+    }
+
+    fn read(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        self.source
+            .read(self.header_len + start, self.header_len + end)
+    }
+
+    fn advise(&self, advice: Advice) {
+        self.source.advise(advice)
+    }
+
+    fn prefetch(&self, start: usize, end: usize) {
+        self.source
+            .prefetch(self.header_len + start, self.header_len + end)
+    }
+}
+
+fn write_json_string(ofs: &mut impl Write, s: &[u8]) -> std::io::Result<()> {
+    ofs.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => ofs.write_all(b"\\\"")?,
+            '\\' => ofs.write_all(b"\\\\")?,
+            '\n' => ofs.write_all(b"\\n")?,
+            '\r' => ofs.write_all(b"\\r")?,
+            '\t' => ofs.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(ofs, "\\u{:04x}", c as u32)?,
+            c => write!(ofs, "{}", c)?,
+        }
+    }
+    ofs.write_all(b"\"")
+}
+
+#[test]
+fn test_line_number() {
+    let database = Database {
+        path: String::new(),
+        source: Box::new(b"h\na\nb\n\nc".to_vec()),
+        header_len: 2,
+        crlf: false,
+        field_names: vec![],
+        line_cache: RefCell::new((0, 0)),
+    };
+    assert_eq!(database.line_number(2), 3);
+    assert_eq!(database.line_number(5), 5);
+    assert_eq!(database.line_number(0), 2);
+}
+
+#[test]
+fn test_write_json_string() {
+    let mut buf = Vec::new();
+    write_json_string(&mut buf, b"a\"b\\c\td\x01").unwrap();
+    assert_eq!(buf, br#""a\"b\\c\td\u0001""#);
+}
+
+// end of the longest run of whole records from start within max_bytes and max_lines,
+// and the number of lines in it
+fn limit_range<S: Source + ?Sized>(
+    database: &S,
+    start: usize,
+    end: usize,
+    max_bytes: usize,
+    max_lines: usize,
+) -> (usize, usize) {
+    let end = match start.saturating_add(max_bytes) < end {
+        true => record_start(database, start + max_bytes).max(start),
+        false => end,
+    };
+    if max_lines == 0 {
+        return (start, 0);
+    }
+    let mut lines = 0;
+    let mut pos = start;
+    while pos < end {
+        let chunk_end = (pos + WRITE_SIZE).min(end);
+        for (offset, &x) in database.read(pos, chunk_end).iter().enumerate() {
+            if x == b'\n' {
+                lines += 1;
+                if lines == max_lines {
+                    return (pos + offset + 1, lines);
+                }
+            }
+        }
+        pos = chunk_end;
+    }
+    if end > start && database.read(end - 1, end)[0] != b'\n' {
+        lines += 1; // the last record without the newline
+    }
+    (end, lines)
+}
+
+#[test]
+fn test_limit_range() {
+    let database = "a\nbb\nccc\ndd".as_bytes();
+    let len = database.len();
+    assert_eq!(
+        limit_range(database, 0, len, usize::MAX, usize::MAX),
+        (len, 4)
+    );
+    assert_eq!(limit_range(database, 0, len, 5, usize::MAX), (5, 2));
+    assert_eq!(limit_range(database, 0, len, 4, usize::MAX), (2, 1));
+    assert_eq!(limit_range(database, 0, len, 1, usize::MAX), (0, 0));
+    assert_eq!(limit_range(database, 2, len, usize::MAX, 2), (9, 2));
+    assert_eq!(limit_range(database, 0, len, usize::MAX, 0), (0, 0));
+}
+
+struct Printer {
+    ofs: BufWriter<File>,
+    delim: Vec<u8>,
+    cut_key: Option<KeyOption>,
+    json: bool,
+    source: bool,
+    byte_offset: bool,
+    line_number: bool,
+    join: bool,
+    exists: bool,
+    prefetch: bool,
+    max_bytes: Option<usize>,
+    max_lines: Option<usize>,
+    truncate: bool,
+    // (bytes, lines) printed for the current query, and whether it has been truncated
+    printed: (usize, usize),
+    truncated: bool,
+    group_separator: Option<Vec<u8>>,
+    // whether anything has been written, and whether since the current query began
+    written: bool,
+    in_block: bool,
+}
+
+impl Printer {
+    // start the result block of the next query
+    fn begin_query(&mut self) {
+        self.in_block = false;
+        self.printed = (0, 0);
+        self.truncated = false;
+    }
+
+    // shrink [start, end) to what is left of the limits for the current query
+    fn limit(
+        &mut self,
+        database: &Database,
+        start: usize,
+        end: usize,
+        query: &str,
+    ) -> Result<usize, String> {
+        if self.max_bytes.is_none() && self.max_lines.is_none() {
+            return Ok(end);
+        }
+        if self.truncated {
+            return Ok(start);
+        }
+        let max_bytes = self.max_bytes.unwrap_or(usize::MAX) - self.printed.0;
+        let max_lines = self.max_lines.unwrap_or(usize::MAX) - self.printed.1;
+        let (limit, lines) = limit_range(database, start, end, max_bytes, max_lines);
+        self.printed = (self.printed.0 + limit - start, self.printed.1 + lines);
+        if limit < end {
+            if !self.truncate {
+                return Err(format!(
+                    "`{}` matches more than the limit; narrow the query or set `--truncate`",
+                    query
+                ));
+            }
+            if verbosity() >= WARN {
+                eprintln!("`{}`: output truncated at the limit", query);
+            }
+            self.truncated = true;
+        }
+        Ok(limit)
+    }
+
+    fn write_separator(&mut self) -> std::io::Result<()> {
+        if let Some(separator) = &self.group_separator {
+            if self.written && !self.in_block {
+                self.ofs.write_all(separator)?;
+                self.ofs.write_all(b"\n")?;
+            }
+        }
+        self.written = true;
+        self.in_block = true;
+        Ok(())
+    }
+
+    // write records in database[start..end] that matched the query
+    fn write(
+        &mut self,
+        database: &Database,
+        start: usize,
+        end: usize,
+        query: &str,
+    ) -> Result<(), String> {
+        if self.exists {
+            return Ok(());
+        }
+        let end = self.limit(database, start, end, query)?;
+        if start == end {
+            return Ok(());
+        }
+        self.write_separator().expect("error writing out");
+        if self.prefetch {
+            database.prefetch(start, end);
+        }
+        if !self.json
+            && !self.source
+            && !self.byte_offset
+            && !self.line_number
+            && self.cut_key.is_none()
+            && !self.join
+            && !database.crlf
+        {
+            for begin in (start..end).step_by(WRITE_SIZE) {
+                self.ofs
+                    .write_all(&database.read(begin, end.min(begin + WRITE_SIZE)))
+                    .expect("error writing out");
+            }
+            if end > start && database.read(end - 1, end)[0] != b'\n' {
+                self.ofs.write_all(b"\n").expect("error writing out");
+            }
+            return Ok(());
+        }
+
+        let mut pos = start;
+        while pos < end {
+            let next = record_end(database, pos);
+            match self.json {
+                true => self.write_json(database, pos, next, query),
+                false => self.write_record(database, pos, next, query),
+            }
             .expect("error writing out");
+            pos = next + 1;
+        }
+        Ok(())
+    }
+
+    fn write_record(
+        &mut self,
+        database: &Database,
+        start: usize,
+        end: usize,
+        query: &str,
+    ) -> std::io::Result<()> {
+        if self.join {
+            self.ofs.write_all(query.as_bytes())?;
+            self.ofs.write_all(&self.delim)?;
+        }
+        if self.source {
+            self.ofs.write_all(database.path.as_bytes())?;
+            self.ofs.write_all(&self.delim)?;
+        }
+        if self.byte_offset {
+            write!(self.ofs, "{}", database.header_len + start)?;
+            self.ofs.write_all(&self.delim)?;
+        }
+        if self.line_number {
+            write!(self.ofs, "{}", database.line_number(start))?;
+            self.ofs.write_all(&self.delim)?;
+        }
+        let record = database.read(start, end);
+        match self.cut_key {
+            Some(ref key_option) => self.ofs.write_all(&key_option.cut_key(&record))?,
+            None => self.ofs.write_all(strip_cr(&record))?,
+        }
+        self.ofs.write_all(b"\n")
+    }
+
+    fn write_json(
+        &mut self,
+        database: &Database,
+        start: usize,
+        end: usize,
+        query: &str,
+    ) -> std::io::Result<()> {
+        let offset = database.header_len;
+        self.ofs.write_all(b"{\"query\":")?;
+        write_json_string(&mut self.ofs, query.as_bytes())?;
+        if self.source {
+            self.ofs.write_all(b",\"source\":")?;
+            write_json_string(&mut self.ofs, database.path.as_bytes())?;
+        }
+        write!(
+            self.ofs,
+            ",\"start\":{},\"end\":{}",
+            offset + start,
+            offset + end
+        )?;
+        if self.line_number {
+            write!(self.ofs, ",\"line\":{}", database.line_number(start))?;
+        }
+        self.ofs.write_all(b",\"fields\":{")?;
+        let record = database.read(start, end);
+        let key_idx = self.cut_key.as_ref().map(|key_option| key_option.key_idx);
+        let mut first = true;
+        for (idx, field) in split(strip_cr(&record), &self.delim)
+            .enumerate()
+            .filter(|&(idx, _)| Some(idx) != key_idx)
+        {
+            if !first {
+                self.ofs.write_all(b",")?;
+            }
+            first = false;
+            match database.field_names.get(idx) {
+                Some(name) => write_json_string(&mut self.ofs, name.as_bytes())?,
+                None => write!(self.ofs, "\"f{}\"", idx + 1)?,
+            }
+            self.ofs.write_all(b":")?;
+            write_json_string(&mut self.ofs, field)?;
+        }
+        self.ofs.write_all(b"}}\n")
+    }
+}
+
+// whether any record matched, and the ranges to print from each database
+type Results = (bool, Vec<Vec<(usize, usize)>>);
+
+// results of recently seen queries, evicting the least recently used
+struct Cache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<Vec<u8>, (u64, Results)>,
+    recency: BTreeMap<u64, Vec<u8>>, // tick of the last use -> key
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Results> {
+        let (tick, results) = self.entries.get_mut(key)?;
+        self.recency.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.recency.insert(self.tick, key.to_vec());
+        Some(results.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, results: Results) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, results));
+    }
+}
+
+#[test]
+fn test_cache() {
+    let mut cache = Cache::new(2);
+    cache.insert(b"a".to_vec(), (true, vec![vec![(0, 1)]]));
+    cache.insert(b"b".to_vec(), (false, vec![vec![]]));
+    assert_eq!(cache.get(b"a"), Some((true, vec![vec![(0, 1)]])));
+    cache.insert(b"c".to_vec(), (true, vec![vec![(2, 3)]]));
+    assert_eq!(cache.get(b"b"), None); // least recently used
+    assert!(cache.get(b"a").is_some());
+    assert!(cache.get(b"c").is_some());
+    assert_eq!(Cache::new(0).get(b"a"), None);
+}
+
+// search the databases for the normalized query q; stops at the first match with `-e`
+fn search(
+    databases: &[Database],
+    query: &str,
+    q: &[u8],
+    program_option: &ProgramOption,
+    mut cursors: Option<&mut [usize]>,
+) -> Results {
+    let key_option = &program_option.key_option;
+    let match_type = &program_option.match_type;
+    let mut matched = false;
+    let mut results = Vec::with_capacity(databases.len());
+    for (idx, database) in databases.iter().enumerate() {
+        if matched && program_option.exists {
+            break;
+        }
+        if matches!(
+            match_type,
+            MatchType::SubstringMatch | MatchType::SuffixMatch
+        ) {
+            let ranges = scan_matches(database, q, key_option, match_type);
+            matched |= !ranges.is_empty();
+            results.push(ranges);
+            continue;
+        }
+        if program_option.glob {
+            let ranges = find_glob_matches(database, q, key_option);
+            matched |= !ranges.is_empty();
+            results.push(ranges);
+            continue;
+        }
+
+        let start = match cursors {
+            Some(ref mut cursors) => {
+                cursors[idx] = gallop(database, cursors[idx], key_option, |x| {
+                    key_option.precedes(x, q, match_type)
+                });
+                cursors[idx]
+            }
+            None => match_start(q, database, key_option, match_type),
+        };
+        let found = find_matches(database, start, q, key_option, match_type);
+        matched |= found.is_some();
+        if let (None, Some(max_distance)) = (found, program_option.fuzzy) {
+            let suggestions = find_fuzzy(database, start, q, key_option, max_distance);
+            if !suggestions.is_empty() && verbosity() >= WARN {
+                let suggestions: Vec<String> = suggestions
+                    .iter()
+                    .map(|key| format!("`{}`", String::from_utf8_lossy(key)))
+                    .collect();
+                eprintln!(
+                    "`{}` not found in `{}`; did you mean {}?",
+                    query,
+                    database.path,
+                    suggestions.join(", ")
+                );
+            }
+        }
+        let ranges = match (found, program_option.nearest) {
+            (Some(range), _) => vec![range],
+            (None, Some(nearest)) => find_nearest(database, start, key_option, nearest),
+            (None, None) => vec![],
+        };
+        if verbosity() >= INFO {
+            eprintln!(
+                "`{}` in `{}`: {} range(s) of {} bytes",
+                query,
+                database.path,
+                ranges.len(),
+                ranges.iter().map(|(start, end)| end - start).sum::<usize>()
+            );
+        }
+        results.push(ranges);
+    }
+    (matched, results)
+}
+
+// returns whether the query matched any record
+fn run_query(
+    printer: &mut Printer,
+    databases: &[Database],
+    query: &str,
+    program_option: &ProgramOption,
+    cursors: Option<&mut [usize]>,
+    cache: &mut Cache,
+) -> Result<bool, String> {
+    let q = program_option.key_option.normalize(query.as_bytes());
+    let (matched, results) = match cache.get(&q) {
+        Some(results) => {
+            if verbosity() >= INFO {
+                eprintln!("`{}`: cached", query);
+            }
+            results
+        }
+        None => {
+            let results = search(databases, query, &q, program_option, cursors);
+            cache.insert(q.into_owned(), results.clone());
+            results
+        }
+    };
+    printer.begin_query();
+    for (database, ranges) in databases.iter().zip(results) {
+        for (start, end) in ranges {
+            printer.write(database, start, end, query)?;
+        }
+    }
+    Ok(matched)
+}
+
+// queries must be sorted in the same order as the databases,
+// so that each database is searched in a single forward pass
+fn run_join(
+    printer: &mut Printer,
+    databases: &[Database],
+    ifs: impl BufRead,
+    program_option: &ProgramOption,
+    cache: &mut Cache,
+) -> Result<bool, String> {
+    let key_option = &program_option.key_option;
+    let mut cursors = vec![0; databases.len()];
+    let mut matched = false;
+    let mut prev = Vec::new();
+    for line in ifs.lines() {
+        let line = line.expect("cannot read queries");
+        let q = key_option.normalize(line.as_bytes());
+        if key_option.compare(&q, &prev) == Ordering::Less {
+            if verbosity() >= WARN {
+                eprintln!(
+                    "queries are not sorted at `{}`; restarting the search",
+                    line
+                );
+            }
+            cursors.iter_mut().for_each(|x| *x = 0);
+        }
+        prev = q.into_owned();
+        matched |= run_query(
+            printer,
+            databases,
+            &line,
+            program_option,
+            Some(&mut cursors),
+            cache,
+        )?;
+        if matched && program_option.exists {
+            break;
+        }
     }
+    Ok(matched)
 }
 
-fn main() {
+fn run_batch(
+    printer: &mut Printer,
+    databases: &[Database],
+    ifs: impl BufRead,
+    program_option: &ProgramOption,
+    cache: &mut Cache,
+) -> Result<bool, String> {
+    let mut matched = false;
+    for line in ifs.lines() {
+        let line = line.expect("cannot read from stdin");
+        matched |= run_query(printer, databases, &line, program_option, None, cache)?;
+        if matched && program_option.exists {
+            break;
+        }
+    }
+    Ok(matched)
+}
+
+fn main() -> ExitCode {
     let program_option = match parse_arguments() {
         Err(ref msg) => {
             eprintln!("{}", msg);
-            return;
+            return ExitCode::from(2);
         }
         Ok(x) => x,
     };
 
-    let database = File::open(program_option.database.clone())
-        .expect(&format!("Failed to open `{}`", program_option.database));
-    let mmap = unsafe {
-        MmapOptions::new().map(&database).expect(&format!(
-            "Failed to mmap `{}`. Make sure it supports mmap",
-            program_option.database
-        ))
+    let databases = match program_option
+        .databases
+        .iter()
+        .map(|path| {
+            let database = Database::open(
+                path,
+                program_option.header,
+                &program_option.key_option.delim,
+                program_option.mmap_window,
+                &program_option.tmpdir,
+            )?;
+            if let Some(advice) = program_option.advice {
+                database.advise(advice);
+            }
+            Ok::<_, String>(database)
+        })
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Err(ref msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::from(2);
+        }
+        Ok(x) => x,
     };
 
     let output_file = "/dev/stdout".to_owned();
-    let mut ofs = BufWriter::new(File::create(output_file).expect("Error writing to stdout"));
-
-    match program_option.query {
-        Some(ref q) => {
-            let start = lower_bound(q, &mmap, program_option.delim, program_option.key_idx);
-            print_matches(
-                &mut ofs,
-                &mmap,
-                start,
-                q.as_bytes(),
-                program_option.key_idx,
-                program_option.delim,
-                &program_option.match_type,
-            )
+    let mut printer = Printer {
+        ofs: BufWriter::new(File::create(output_file).expect("Error writing to stdout")),
+        delim: program_option.key_option.delim.clone(),
+        cut_key: match program_option.cut_key {
+            true => Some(KeyOption::new(
+                &program_option.key_option.delim,
+                program_option.key_option.key_idx,
+            )),
+            false => None,
+        },
+        json: program_option.json,
+        source: program_option.source,
+        byte_offset: program_option.byte_offset,
+        line_number: program_option.line_number,
+        join: program_option.join.is_some(),
+        exists: program_option.exists,
+        prefetch: program_option.advice.is_some(),
+        max_bytes: program_option.max_bytes,
+        max_lines: program_option.max_lines,
+        truncate: program_option.truncate,
+        printed: (0, 0),
+        truncated: false,
+        group_separator: program_option
+            .group_separator
+            .as_ref()
+            .map(|x| x.as_bytes().to_vec()),
+        written: false,
+        in_block: false,
+    };
+
+    let mut cache = Cache::new(program_option.cache_size);
+    let matched = match (&program_option.query, &program_option.join) {
+        (Some(q), _) => run_query(
+            &mut printer,
+            &databases,
+            q,
+            &program_option,
+            None,
+            &mut cache,
+        ),
+        (None, Some(path)) => {
+            let path = match path.as_str() {
+                "-" => "/dev/stdin",
+                _ => path,
+            };
+            let ifs = BufReader::new(File::open(path).expect("Error reading query file"));
+            run_join(&mut printer, &databases, ifs, &program_option, &mut cache)
         }
-        None => {
-            let ifs = BufReader::new(
-                File::open(program_option.database.clone()).expect("Error reading input file"),
-            );
-            ifs.lines().for_each(|line| {
-                let line = line.expect("cannot read from stdin");
-                let start = lower_bound(&line, &mmap, program_option.delim, program_option.key_idx);
-                print_matches(
-                    &mut ofs,
-                    &mmap,
-                    start,
-                    line.as_bytes(),
-                    program_option.key_idx,
-                    program_option.delim,
-                    &program_option.match_type,
-                );
-            });
+        (None, None) => {
+            let ifs = BufReader::new(File::open("/dev/stdin").expect("Error reading stdin"));
+            run_batch(&mut printer, &databases, ifs, &program_option, &mut cache)
+        }
+    };
+
+    match matched {
+        Err(ref msg) => {
+            eprintln!("{}", msg);
+            ExitCode::from(2)
         }
+        Ok(matched) if program_option.exists && !matched => ExitCode::FAILURE,
+        Ok(_) => ExitCode::SUCCESS,
     }
 }
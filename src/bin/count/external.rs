@@ -0,0 +1,219 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use suputils::temp_file;
+
+// number of runs merged into one at a time, so that the open temporary files stay well
+// below `ulimit -n` however many times the counts spill
+const FAN_IN: usize = 64;
+
+/// Counts of the lines that are spilled to a temporary file in a run sorted by line
+/// whenever the lines in memory take more than max_memory bytes.
+pub struct ExternalCounts {
+    map: HashMap<String, usize>,
+    memory: usize, // estimated bytes of the map
+    max_memory: usize,
+    tmpdir: PathBuf,
+    runs: Vec<(usize, BufReader<File>)>, // (level, run); a run of level L + 1 merges FAN_IN of L
+}
+
+impl ExternalCounts {
+    pub fn new(max_memory: usize, tmpdir: &Path) -> Self {
+        Self {
+            map: HashMap::new(),
+            memory: 0,
+            max_memory,
+            tmpdir: tmpdir.to_owned(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, line: String, count: usize) -> io::Result<()> {
+        match self.map.get_mut(&line) {
+            Some(total) => *total += count,
+            None => {
+                self.memory += line.len() + std::mem::size_of::<(String, usize)>();
+                self.map.insert(line, count);
+            }
+        }
+        if self.memory >= self.max_memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let entries = self.drain_sorted();
+        let run = self.write_run(entries.into_iter().map(Ok))?;
+        self.runs.push((0, run));
+        self.memory = 0;
+        // merge the last FAN_IN runs whenever they are of the same level, which leaves
+        // fewer than FAN_IN runs of each level
+        loop {
+            let level = self.runs.last().map_or(0, |(level, _)| *level);
+            let same = self.runs.iter().rev().take_while(|(x, _)| *x == level);
+            if same.count() < FAN_IN {
+                return Ok(());
+            }
+            let runs = self.runs.split_off(self.runs.len() - FAN_IN);
+            let runs = runs
+                .into_iter()
+                .map(|(_, reader)| read_run(reader))
+                .collect();
+            let run = self.write_run(MergedCounts::new(runs)?)?;
+            self.runs.push((level + 1, run));
+        }
+    }
+
+    // write the entries sorted by line to a temporary file, and rewind it for reading
+    fn write_run(
+        &self,
+        entries: impl Iterator<Item = io::Result<(String, usize)>>,
+    ) -> io::Result<BufReader<File>> {
+        let mut file = temp_file(&self.tmpdir)?;
+        let mut writer = BufWriter::new(&mut file);
+        for entry in entries {
+            let (line, count) = entry?;
+            write_number(&mut writer, count)?;
+            write_number(&mut writer, line.len())?;
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        file.rewind()?;
+        Ok(BufReader::new(file))
+    }
+
+    fn drain_sorted(&mut self) -> Vec<(String, usize)> {
+        let mut entries: Vec<_> = self.map.drain().collect();
+        entries.sort_unstable();
+        entries
+    }
+
+    /// the counts of all the lines in byte order of the lines, merged from the runs
+    pub fn into_sorted(mut self) -> io::Result<MergedCounts> {
+        let mut runs: Vec<Run> = Vec::new();
+        runs.push(Box::new(self.drain_sorted().into_iter().map(Ok)));
+        runs.extend(self.runs.into_iter().map(|(_, reader)| read_run(reader)));
+        MergedCounts::new(runs)
+    }
+}
+
+type Run = Box<dyn Iterator<Item = io::Result<(String, usize)>>>;
+
+fn read_run(mut reader: BufReader<File>) -> Run {
+    Box::new(std::iter::from_fn(move || {
+        read_entry(&mut reader).transpose()
+    }))
+}
+
+pub struct MergedCounts {
+    runs: Vec<Run>,
+    heap: BinaryHeap<Reverse<(String, usize, usize)>>, // (line, run index, count)
+}
+
+impl MergedCounts {
+    fn new(runs: Vec<Run>) -> io::Result<Self> {
+        let mut merged = MergedCounts {
+            runs,
+            heap: BinaryHeap::new(),
+        };
+        for idx in 0..merged.runs.len() {
+            merged.advance(idx)?;
+        }
+        Ok(merged)
+    }
+
+    // push the next line of the run to the heap
+    fn advance(&mut self, idx: usize) -> io::Result<()> {
+        if let Some(entry) = self.runs[idx].next() {
+            let (line, count) = entry?;
+            self.heap.push(Reverse((line, idx, count)));
+        }
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> io::Result<Option<(String, usize)>> {
+        let Reverse((line, idx, mut total)) = match self.heap.pop() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        self.advance(idx)?;
+        // the same line from the other runs
+        while let Some(Reverse((next, ..))) = self.heap.peek() {
+            if *next != line {
+                break;
+            }
+            let Reverse((_, idx, count)) = self.heap.pop().unwrap();
+            total += count;
+            self.advance(idx)?;
+        }
+        Ok(Some((line, total)))
+    }
+}
+
+impl Iterator for MergedCounts {
+    type Item = io::Result<(String, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+fn write_number(ofs: &mut impl Write, number: usize) -> io::Result<()> {
+    ofs.write_all(&(number as u64).to_le_bytes())
+}
+
+fn read_number(ifs: &mut impl Read) -> io::Result<usize> {
+    let mut bytes = [0; 8];
+    ifs.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes) as usize)
+}
+
+// the next count and line of the run, or none at its end
+fn read_entry(ifs: &mut impl Read) -> io::Result<Option<(String, usize)>> {
+    let count = match read_number(ifs) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut bytes = vec![0; read_number(ifs)?];
+    ifs.read_exact(&mut bytes)?;
+    let line =
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some((line, count)))
+}
+
+#[test]
+fn test_external_counts() {
+    // spill every other line
+    let size = 2 * (1 + std::mem::size_of::<(String, usize)>());
+    let mut counts = ExternalCounts::new(size, &std::env::temp_dir());
+    for line in ["b", "a", "c", "a", "b", "b"] {
+        counts.push(line.to_owned(), 1).unwrap();
+    }
+    assert_eq!(counts.runs.len(), 2);
+    let merged: Vec<_> = counts.into_sorted().unwrap().map(Result::unwrap).collect();
+    let expected = [("a", 2), ("b", 3), ("c", 1)].map(|(line, count)| (line.to_owned(), count));
+    assert_eq!(merged, expected);
+
+    // spill every line, so that the runs are merged by FAN_IN at a time
+    let mut counts = ExternalCounts::new(1, &std::env::temp_dir());
+    for x in 0..5 * FAN_IN {
+        counts.push(format!("{:03}", x % 100), 1).unwrap();
+    }
+    assert!(counts.runs.len() < FAN_IN, "{}", counts.runs.len());
+    let merged: Vec<_> = counts.into_sorted().unwrap().map(Result::unwrap).collect();
+    let expected: Vec<_> = (0..100)
+        .map(|x| {
+            (
+                format!("{:03}", x),
+                5 * FAN_IN / 100 + usize::from(x < 5 * FAN_IN % 100),
+            )
+        })
+        .collect();
+    assert_eq!(merged, expected);
+}
@@ -0,0 +1,2140 @@
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use suputils::regex::Regex;
+use suputils::{compare_numeric, json_string, parse_size, quote_csv};
+
+mod external;
+mod normalize;
+mod sketch;
+mod time;
+
+use external::ExternalCounts;
+use normalize::{normalize, Form};
+use sketch::{HyperLogLog, SpaceSaving};
+use time::{Bucket, TimeParser};
+
+#[derive(Parser)]
+#[command(name = "topk")]
+#[command(author = "TechHara")]
+#[command(version = "0.1.0")]
+#[command(
+    about = "Count occurrence of each line. Input does not need to be sorted.
+
+    $ cat input
+    three
+    one
+    two
+    three
+    two
+    three
+
+    # prints the count followed by the line
+    $ count input
+    3	three
+    1	one
+    2	two
+
+    # set `--sort-key` to print in the order of the lines, or `--numeric` for numeric order
+    $ count --sort-key input
+    1	one
+    3	three
+    2	two
+
+    # set `-n N` to print only the N most frequent lines, most frequent first,
+    # with the lines of the same count in byte order
+    $ count -n 2 input
+    3	three
+    2	two
+
+    # set `--min N` and/or `--max N` to print only the lines counted within the range
+    $ count --min 2 input
+    3	three
+    2	two
+
+    # set `--percent` to print the share of each line in all lines counted,
+    # followed by the cumulative share if the output is sorted
+    $ count --percent -n 3 input
+    3	50.00	50.00	three
+    2	33.33	83.33	two
+    1	16.67	100.00	one
+
+    # set `--trim` and/or `--squeeze-space` to count lines that differ only in spacing together
+    $ printf '  two\\n two  \\ntwo   three\\ntwo three\\n' | count --trim --squeeze-space --sort-key
+    2	two
+    2	two three
+
+    # set `--normalize nfc` or `--normalize nfkc` to count the lines equivalent in Unicode together
+    $ printf 'caf\\xc3\\xa9\\ncafe\\xcc\\x81\\n' | count --normalize nfc
+    2	café
+
+    # set `--approx-top N` to estimate the N most frequent lines with a fixed amount of memory
+    # when there are too many distinct lines to count; `--error EPS` bounds the overestimate
+    $ count --approx-top 1 input
+    3	three
+
+    # set `--cardinality` to print only an estimate of the number of distinct lines
+    # using 2^P bytes of memory, where `--precision P` trades memory for accuracy
+    $ count --cardinality input
+    3
+
+    # set `--merge` to sum up the counts printed by count, e.g., for sharded inputs
+    $ cat <(count input) <(count input) | count --merge --sort-key
+    2	one
+    6	three
+    4	two
+
+    # set `--weight-field N` to count each line by the integer in its Nth field instead of by 1,
+    # e.g., to sum up the bytes per URL; the field is removed from the line
+    $ cat requests
+    /index.html	512
+    /logo.png	2048
+    /index.html	512
+    $ count --weight-field 2 --sort-key requests
+    1024	/index.html
+    2048	/logo.png
+
+    # set `--stable` to print the lines in the order they first appear
+    $ count --stable input
+    3	three
+    1	one
+    2	two
+
+    # set `--format` to print the line before the count with `line-count`,
+    # only the counts with `count`, or only the distinct lines with `line`
+    $ count --format line-count --sort-key input
+    one	1
+    three	3
+    two	2
+
+    # set `-w` to count the words in the lines, i.e., the runs of letters, digits, and underscores,
+    # or set `--token-regex R` to count the matches of the regex instead
+    $ printf 'one, two\\nTwo two!\\n' | count -w --sort-key
+    1	Two
+    1	one
+    2	two
+    $ printf 'GET /a 200\\nGET /b 404\\n' | count -w --token-regex ' ([0-9]{3})$'
+    1	200
+    1	404
+
+    # set `--ngram N` to count the sequences of N words in the lines, or of N characters with `--chars`,
+    # where `--pad SYMBOL` marks the start and the end of each line with N - 1 symbols
+    $ printf 'to be or not to be\\n' | count --ngram 2 --sort-key
+    1	be or
+    1	not to
+    1	or not
+    2	to be
+    $ printf 'abab\\n' | count --ngram 2 --chars --pad _ --sort-key
+    1	_a
+    2	ab
+    1	b_
+    1	ba
+
+    # set `--sorted` for input whose identical lines are adjacent, as in sorted input,
+    # to count each run of them without keeping the lines in memory; it fails if a line
+    # comes before the previous one in byte order, or warns with `--sorted=warn`
+    $ sort input | count --sorted
+    1	one
+    3	three
+    2	two
+
+    # set `--match REGEX` to count only the lines that match the POSIX extended regex,
+    # and/or `--exclude REGEX` to skip the lines that match it, before anything else
+    $ count --match '^t' --exclude 'ee$' --sort-key input
+    2	two
+
+    # set `--extract REGEX` to count the first match of the POSIX extended regex in each line,
+    # or its first parenthesized group if any; the lines without a match are skipped,
+    # or counted as SENTINEL with `--no-match SENTINEL`
+    $ cat access.log
+    GET /a HTTP/1.1 200 512
+    GET /b HTTP/1.1 404 0
+    GET /c HTTP/1.1 200 128
+    $ count --extract ' ([0-9]{3}) ' --sort-key access.log
+    2	200
+    1	404
+
+    # set `--distinct-by A,B` to count the distinct values of field B for each value of field A,
+    # or set `--approx` as well to estimate them in a fixed amount of memory per value of A
+    $ cat visits
+    /index.html	alice
+    /index.html	bob
+    /index.html	alice
+    /logo.png	bob
+    $ count --distinct-by 1,2 --sort-key visits
+    2	/index.html
+    1	/logo.png
+
+    # set `--time-field N` and `--bucket UNIT` to count the lines by the time in their Nth field,
+    # truncated to the second, minute, hour, or day; the time is Unix time or ISO 8601 unless
+    # `--time-format` gives its strptime format, and anything after it in the field is ignored
+    $ cat times
+    2024-05-01T12:00:05	GET /a
+    2024-05-01T12:00:48	GET /b
+    2024-05-01T12:01:10	GET /a
+    $ count --time-field 1 --bucket minute --sort-key times
+    2	2024-05-01T12:00
+    1	2024-05-01T12:01
+    $ printf '1 - - [01/May/2024:12:00:05 +0000]\\n' | count -d ' ' --time-field 4 --bucket day \\
+        --time-format '[%d/%b/%Y:%H:%M:%S'
+    1 2024-05-01
+
+    # set `--field N` to count the Nth field of each line instead of the line, and `--bin WIDTH`
+    # to count its numeric values in bins of WIDTH, each printed as its lower bound
+    $ cat latency
+    /a	12.5
+    /b	3
+    /a	18
+    /a	27
+    $ count --field 2 --bin 10 --sort-key --numeric latency
+    1	0
+    2	10
+    1	20
+
+    # set `--prefix N` to count the lines by their first N characters, or bytes with `--bytes`,
+    # e.g., to count the IP addresses by network
+    $ printf '10.1.2.3\\n10.1.9.9\\n192.168.0.1\\n' | count --prefix 4 --sort-key
+    2	10.1
+    1	192.
+
+    # set `--lengths` to count the lines by their lengths in characters, or bytes with `--bytes`,
+    # e.g., to spot truncated records, and `--bin WIDTH` to count the lengths in bins of WIDTH
+    $ printf 'a\\nbb\\ncc\\nddd\\n' | count --lengths --sort-key --numeric
+    1	1
+    2	2
+    1	3
+    $ printf 'a\\nbb\\ncc\\nddd\\n' | count --lengths --bin 2 --sort-key --numeric
+    1	0
+    3	2
+
+    # set `--json` to print each line and its count as a JSON object,
+    # or `--csv` to print them as CSV, with the line quoted as necessary
+    $ count --json --sort-key input
+    {\"count\": 1, \"value\": \"one\"}
+    {\"count\": 3, \"value\": \"three\"}
+    {\"count\": 2, \"value\": \"two\"}
+    $ printf 'a,b\\nc\\n' | count --csv --sort-key
+    1,\"a,b\"
+    1,c
+
+    # set `--summary` to print the total count, the number of distinct lines, and the count
+    # and the most frequent line after the lines, or `--summary=stderr` to print them to stderr
+    $ count --summary --sort-key input
+    1	one
+    3	three
+    2	two
+    total	6
+    distinct	3
+    max	3
+    top	three
+
+    # set `--entropy` to print the Shannon entropy of the counts in bits, the perplexity,
+    # i.e., 2 to the entropy, and the Gini coefficient of the counts after the lines,
+    # or `--entropy=only` to print them instead of the lines
+    $ count --entropy=only input
+    entropy	1.4591
+    perplexity	2.7495
+    gini	0.2222
+
+    # give several input files to count all of their lines, or set `--by-file` to print
+    # the count in each file followed by the total
+    $ count --by-file --sort-key input <(echo one)
+    1	1	2	one
+    3	0	3	three
+    2	0	2	two
+
+    # set `-z` for NUL-terminated records, e.g., for file names from `find -print0`
+    $ printf 'a\\nb\\0c\\0a\\nb\\0' | count -z --sort-key | tr '\\0' '\\n'
+    2	a
+    b
+    1	c
+
+    # set `--threads N` to count huge input in N threads
+    $ count --threads 8 huge-input
+
+    # set `--window N` to count only the last N lines, or `--window Ts` to count only the lines
+    # read in the last T seconds, and send SIGUSR1 to print the counts in the window at any time
+    # besides at the end, e.g., to watch a live log; each printout follows an empty line
+    $ count --window 3 --sort-key input
+    2	three
+    1	two
+    $ tail -f app.log | count --window 60s -n 10 &
+    $ kill -USR1 %1
+
+    # set `--time-field N` as well to count the lines of the last T seconds by the time
+    # in the Nth field instead, e.g., to replay a log
+    $ cat requests
+    2024-05-01T12:00:00	GET
+    2024-05-01T12:00:30	POST
+    2024-05-01T12:00:50	GET
+    2024-05-01T12:01:40	GET
+    2024-05-01T12:01:45	PUT
+    $ count --window 60s --time-field 1 --field 2 --sort-key requests
+    2	GET
+    1	PUT
+
+    # set `--flush-every N` to print the counts every N lines, or `--flush-every Ts` every T seconds,
+    # each after a line of `#` and the UTC time, e.g., for a live log that never ends,
+    # and set `--reset` as well to count anew after each printout
+    $ count --flush-every 4 --reset --sort-key input
+    # 2024-05-01T12:00:05Z
+    1	one
+    2	three
+    1	two
+    # 2024-05-01T12:00:05Z
+    1	three
+    1	two
+    $ tail -f app.log | count --flush-every 60s --reset -n 10
+
+    # set `--external` for input with more distinct lines than fit in memory; the counts are
+    # spilled in runs of `--max-memory` to `--tmpdir`, and the output is sorted by line
+    $ count --external --max-memory 4G --tmpdir /scratch huge-input
+"
+)]
+struct Arguments {
+    /// output delimiter, which also separates the fields of the input for `--merge` and `--weight-field`
+    /// [default: \t, or `,` with `--csv`]
+    #[arg(short)]
+    delimiter: Option<char>,
+    /// suppress empty line
+    #[arg(short, default_value_t = false)]
+    suppress: bool,
+    /// records of the input and output are terminated by NUL instead of newline
+    #[arg(short, default_value_t = false)]
+    zero_terminated: bool,
+    /// print in the byte order of the lines instead of in arbitrary order
+    #[arg(long, default_value_t = false)]
+    sort_key: bool,
+    /// with `--sort-key`, order the lines numerically; those that are not numbers come last
+    #[arg(long, default_value_t = false, requires = "sort_key")]
+    numeric: bool,
+    /// print only the N most frequent lines in the order of their counts, and then of the lines,
+    /// unless `--sort-key` is set
+    #[arg(short = 'n', value_name = "N")]
+    top: Option<usize>,
+    /// print only the lines that occur at least N times
+    #[arg(long = "min", value_name = "N")]
+    min_count: Option<usize>,
+    /// print only the lines that occur at most N times
+    #[arg(long = "max", value_name = "N")]
+    max_count: Option<usize>,
+    /// print the percentage of each line in all lines after the count, and then
+    /// the cumulative percentage if the output is ordered by `-n`, `--sort-key` or `--stable`
+    #[arg(long, default_value_t = false)]
+    percent: bool,
+    /// strip leading and trailing whitespace of each line before counting
+    #[arg(long, default_value_t = false)]
+    trim: bool,
+    /// squeeze repeated spaces of each line into one before counting
+    #[arg(long, default_value_t = false)]
+    squeeze_space: bool,
+    /// convert each line into the Unicode normalization form before counting
+    #[arg(long, value_name = "FORM", value_parser = ["nfc", "nfkc"])]
+    normalize: Option<String>,
+    /// estimate the N most frequent lines with a fixed number of counters instead of
+    /// counting every distinct line; the counts may be overestimated
+    #[arg(long, value_name = "N", conflicts_with = "top")]
+    approx_top: Option<usize>,
+    /// with `--approx-top`, overestimate each count by at most EPS times the number of lines,
+    /// using 1/EPS counters
+    #[arg(
+        long = "error",
+        value_name = "EPS",
+        default_value_t = 0.0001,
+        requires = "approx_top"
+    )]
+    error_rate: f64,
+    /// print only the estimated number of distinct lines, counted in a fixed amount of memory
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["sort_key", "top", "min_count", "max_count", "percent", "approx_top"]
+    )]
+    cardinality: bool,
+    /// with `--cardinality` or `--approx`, use 2^P registers; the standard error is about
+    /// 1.04 / sqrt(2^P) [default: 12]
+    #[arg(long, value_name = "P", value_parser = clap::value_parser!(u32).range(4..=18))]
+    precision: Option<u32>,
+    /// read the lines preceded by their counts and the delimiter, as printed by count,
+    /// and sum up the counts of each line
+    #[arg(long, default_value_t = false)]
+    merge: bool,
+    /// count each line by the non-negative integer in its Nth field instead of by 1,
+    /// without the field itself
+    #[arg(long, value_name = "N", conflicts_with = "merge")]
+    weight_field: Option<usize>,
+    /// count in N threads, each with its own batches of lines, and add up their counts at the end
+    #[arg(long, value_name = "N", conflicts_with_all = ["approx_top", "cardinality"])]
+    threads: Option<usize>,
+    /// print the lines in the order they first appear instead of in arbitrary order
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["sort_key", "top", "approx_top", "cardinality"]
+    )]
+    stable: bool,
+    /// print the count followed by the line, the line followed by the count,
+    /// only the count, or only the line
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_parser = ["count-line", "line-count", "count", "line"],
+        default_value = "count-line"
+    )]
+    format: String,
+    /// count the words in each line instead of the lines, where a word is a run of letters,
+    /// digits, and underscores
+    #[arg(short, default_value_t = false, conflicts_with = "merge")]
+    words: bool,
+    /// with `-w` or `--ngram`, take the matches of the POSIX extended regex as the words,
+    /// or their first parenthesized groups if any
+    #[arg(long, value_name = "R")]
+    token_regex: Option<String>,
+    /// count the sequences of N consecutive words in each line, joined by a space
+    #[arg(long, value_name = "N", conflicts_with = "merge")]
+    ngram: Option<usize>,
+    /// with `--ngram`, count the sequences of N characters instead of words
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "ngram",
+        conflicts_with = "token_regex"
+    )]
+    chars: bool,
+    /// with `--ngram`, add N - 1 symbols before and after the words or characters of each line
+    #[arg(long, value_name = "SYMBOL", requires = "ngram")]
+    pad: Option<String>,
+    /// count the runs of identical lines in sorted input as they end, without keeping the lines;
+    /// fail if a line comes before the previous one in byte order, or just warn with `=warn`
+    #[arg(
+        long,
+        value_name = "ACTION",
+        value_parser = ["error", "warn"],
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "error",
+        conflicts_with_all = [
+            "top", "approx_top", "cardinality", "sort_key", "stable", "threads", "percent",
+            "words", "ngram",
+        ]
+    )]
+    sorted: Option<String>,
+    /// count only the lines that match the POSIX extended regex
+    #[arg(long = "match", value_name = "REGEX")]
+    match_regex: Option<String>,
+    /// skip the lines that match the POSIX extended regex
+    #[arg(long = "exclude", value_name = "REGEX")]
+    exclude_regex: Option<String>,
+    /// count the first match of the POSIX extended regex in each line instead of the line,
+    /// or its first parenthesized group if any; skip the lines without a match
+    #[arg(long, value_name = "REGEX")]
+    extract: Option<String>,
+    /// with `--extract`, count the lines without a match as SENTINEL instead of skipping them
+    #[arg(long, value_name = "SENTINEL", requires = "extract")]
+    no_match: Option<String>,
+    /// count the first N characters of each line instead of the whole line
+    #[arg(long, value_name = "N", conflicts_with_all = ["words", "ngram", "distinct_by"])]
+    prefix: Option<usize>,
+    /// count the lines by their lengths instead of their contents
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["words", "ngram", "prefix", "distinct_by"]
+    )]
+    lengths: bool,
+    /// with `--prefix` or `--lengths`, count in bytes instead of characters
+    #[arg(long, default_value_t = false)]
+    bytes: bool,
+    /// count the distinct values of field B for each value of field A instead of the lines,
+    /// where the fields are separated by the delimiter
+    #[arg(
+        long,
+        value_name = "A,B",
+        conflicts_with_all = [
+            "merge", "weight_field", "approx_top", "cardinality", "threads", "sorted", "percent",
+            "words", "ngram", "extract",
+        ]
+    )]
+    distinct_by: Option<String>,
+    /// with `--distinct-by`, estimate the number of distinct values with HyperLogLog of
+    /// 2^P bytes for each value of A
+    #[arg(long, default_value_t = false, requires = "distinct_by")]
+    approx: bool,
+    /// print the total count, the number of distinct lines, the highest count,
+    /// and the line with it after the lines, or to stderr with `=stderr`
+    #[arg(
+        long,
+        value_name = "TO",
+        value_parser = ["stdout", "stderr"],
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "stdout",
+        conflicts_with_all = ["approx_top", "cardinality", "distinct_by"]
+    )]
+    summary: Option<String>,
+    /// count each line by the time in its Nth field, truncated to the `--bucket`, instead of the line;
+    /// with `--window Ts`, keep the lines of the last T seconds of the time instead
+    #[arg(long, value_name = "N", conflicts_with = "distinct_by")]
+    time_field: Option<usize>,
+    /// with `--time-field`, the unit to truncate the time to
+    #[arg(
+        long,
+        value_name = "UNIT",
+        value_parser = ["second", "minute", "hour", "day"],
+        requires = "time_field",
+        conflicts_with_all = ["merge", "words", "ngram", "extract"]
+    )]
+    bucket: Option<String>,
+    /// with `--time-field`, the strptime format of the time
+    /// [default: Unix time, or ISO 8601 such as 2024-05-01T12:00:05]
+    #[arg(long, value_name = "STRPTIME", requires = "time_field")]
+    time_format: Option<String>,
+    /// count the Nth field of each line instead of the line, where the fields are separated
+    /// by the delimiter
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["merge", "bucket", "distinct_by"]
+    )]
+    field: Option<usize>,
+    /// with `--field` or `--lengths`, count the numeric values or the lengths in bins of WIDTH,
+    /// each printed as its lower bound
+    #[arg(long, value_name = "WIDTH")]
+    bin: Option<String>,
+    /// print the Shannon entropy in bits, the perplexity, and the Gini coefficient of the counts
+    /// after the lines, or instead of them with `=only`
+    #[arg(
+        long,
+        value_name = "WHEN",
+        value_parser = ["after", "only"],
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "after",
+        conflicts_with_all = ["approx_top", "cardinality", "distinct_by", "window", "flush_every"]
+    )]
+    entropy: Option<String>,
+    /// print each line and its count, and its percentages with `--percent`, as a JSON object
+    #[arg(long, default_value_t = false, conflicts_with_all = ["format", "csv"])]
+    json: bool,
+    /// print the columns as CSV, quoting the line if it contains the delimiter, a double quote,
+    /// or a line break
+    #[arg(long, default_value_t = false)]
+    csv: bool,
+    /// print the count of each line in each input file, followed by the total count
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = [
+            "approx_top", "cardinality", "sorted", "threads", "distinct_by", "percent", "summary",
+            "json",
+        ]
+    )]
+    by_file: bool,
+    /// for input with too many distinct lines to count in memory, spill the counts to temporary
+    /// files in runs sorted by line and merge them; the output is sorted by line
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = [
+            "numeric", "approx_top", "cardinality", "threads", "stable", "sorted", "distinct_by",
+            "by_file",
+        ]
+    )]
+    external: bool,
+    /// with `--external`, directory for the temporary files [default: system temp directory]
+    #[arg(long, value_name = "DIR", requires = "external")]
+    tmpdir: Option<PathBuf>,
+    /// with `--external`, memory to count in before spilling to disk, e.g., 512M or 4G
+    /// [default: 256M]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "external")]
+    max_memory: Option<usize>,
+    /// count only the last N lines, or the lines read in the last T seconds with `Ts`,
+    /// or of the last T seconds of the times with `--time-field`, and print the counts whenever SIGUSR1 is received as well as at the end
+    #[arg(
+        long,
+        value_name = "N|Ts",
+        value_parser = parse_span,
+        conflicts_with_all = [
+            "approx_top", "cardinality", "threads", "stable", "sorted", "distinct_by", "summary",
+            "by_file", "external",
+        ]
+    )]
+    window: Option<Span>,
+    /// print the counts every N lines, or every T seconds with `Ts`, after a line of `#`
+    /// and the UTC time, as well as whenever SIGUSR1 is received and at the end
+    #[arg(
+        long,
+        value_name = "N|Ts",
+        value_parser = parse_span,
+        conflicts_with_all = [
+            "approx_top", "cardinality", "threads", "stable", "sorted", "distinct_by", "summary",
+            "by_file", "external",
+        ]
+    )]
+    flush_every: Option<Span>,
+    /// with `--flush-every`, count anew after each printout
+    #[arg(long, default_value_t = false, requires = "flush_every")]
+    reset: bool,
+    /// Input files; If omitted, read from stdin
+    inputs: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    CountLine,
+    LineCount,
+    Count,
+    Line,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OnUnsorted {
+    Error,
+    Warn,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SummaryTo {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EntropyWhen {
+    After,
+    Only,
+}
+
+struct ProgramOption {
+    delim: String,
+    suppress: bool,
+    record_delim: char,
+    sort_key: bool,
+    numeric: bool,
+    top: Option<usize>,
+    min_count: usize,
+    max_count: usize,
+    percent: bool,
+    trim: bool,
+    squeeze_space: bool,
+    normalize: Option<Form>,
+    approx_top: Option<usize>,
+    capacity: usize,
+    cardinality: Option<u32>,
+    merge: bool,
+    weight_idx: Option<usize>, // 0-index
+    threads: usize,
+    stable: bool,
+    format: Format,
+    ngram: Option<usize>, // 1 for `-w`
+    token_regex: Option<Regex>,
+    chars: bool,
+    pad: Option<String>,
+    sorted: Option<OnUnsorted>,
+    json: bool,
+    csv: bool,
+    match_regex: Option<Regex>,
+    exclude_regex: Option<Regex>,
+    extract: Option<Regex>,
+    no_match: Option<String>,
+    prefix: Option<usize>,
+    lengths: bool,
+    bytes: bool,
+    distinct_idx: Option<(usize, usize)>, // 0-index
+    approx: Option<u32>,                  // precision of HyperLogLog
+    summary: Option<SummaryTo>,
+    entropy: Option<EntropyWhen>,
+    time_idx: Option<usize>,   // 0-index
+    bucket: Option<Bucket>,    // to count the times in, with `--time-field`
+    field_idx: Option<usize>,  // 0-index
+    bin: Option<(f64, usize)>, // width and the number of its decimal places
+    time_parser: TimeParser,
+    by_file: bool,
+    external: bool,
+    tmpdir: PathBuf,
+    max_memory: usize,
+    window: Option<Span>,
+    flush_every: Option<Span>,
+    reset: bool,
+    input_files: Vec<String>,
+}
+
+// memory to count in with `--external` unless `--max-memory` is set
+const MAX_MEMORY: usize = 256 << 20;
+
+fn parse_arguments() -> Result<ProgramOption, String> {
+    let args = Arguments::parse();
+    let mut input_files: Vec<String> = args
+        .inputs
+        .into_iter()
+        .map(|input| match input.as_str() {
+            "-" => "/dev/stdin".to_owned(),
+            _ => input,
+        })
+        .collect();
+    if input_files.is_empty() {
+        input_files.push("/dev/stdin".to_owned());
+    }
+
+    let min_count = args.min_count.unwrap_or(0);
+    let max_count = args.max_count.unwrap_or(usize::MAX);
+    if min_count > max_count {
+        return Err("--min must not be greater than --max".to_owned());
+    }
+    if !(args.error_rate > 0.0 && args.error_rate < 1.0) {
+        return Err("--error must be between 0 and 1".to_owned());
+    }
+    if args.weight_field == Some(0) {
+        return Err("weight field must be 1 or greater".to_owned());
+    }
+    if args.time_field == Some(0) {
+        return Err("time field must be 1 or greater".to_owned());
+    }
+    let time_window = matches!(args.window, Some(Span::Time(_)));
+    if args.time_field.is_some() && args.bucket.is_none() && !time_window {
+        return Err("--time-field requires `--bucket` or `--window Ts`".to_owned());
+    }
+    if args.field == Some(0) {
+        return Err("field must be 1 or greater".to_owned());
+    }
+    if args.bin.is_some() && args.field.is_none() && !args.lengths {
+        return Err("--bin requires either --field or --lengths".to_owned());
+    }
+    if args.bytes && args.prefix.is_none() && !args.lengths {
+        return Err("--bytes requires either --prefix or --lengths".to_owned());
+    }
+    let bin = match args.bin.as_deref() {
+        Some(width) => match width.parse::<f64>() {
+            Ok(x) if x > 0.0 && x.is_finite() => {
+                let decimals = width
+                    .split_once('.')
+                    .map_or(0, |(_, fraction)| fraction.len());
+                Some((x, decimals))
+            }
+            _ => return Err("--bin must be a positive number".to_owned()),
+        },
+        None => None,
+    };
+    if args.percent && args.format == "line" {
+        return Err("--percent cannot be used with --format line".to_owned());
+    }
+    if args.ngram == Some(0) {
+        return Err("--ngram must be positive".to_owned());
+    }
+    if args.token_regex.is_some() && !args.words && args.ngram.is_none() {
+        return Err("--token-regex requires either -w or --ngram".to_owned());
+    }
+    if args.precision.is_some() && !args.cardinality && !args.approx {
+        return Err("--precision requires either --cardinality or --approx".to_owned());
+    }
+    let precision = args.precision.unwrap_or(12);
+    let distinct_idx = match args.distinct_by.as_deref() {
+        Some(fields) => Some(parse_field_pair(fields)?),
+        None => None,
+    };
+    let capacity = (1.0 / args.error_rate).ceil() as usize;
+
+    Ok(ProgramOption {
+        delim: match (args.delimiter, args.csv) {
+            (Some(delim), _) => delim.to_string(),
+            (None, true) => ",".to_owned(),
+            (None, false) => "\t".to_owned(),
+        },
+        suppress: args.suppress,
+        record_delim: match args.zero_terminated {
+            true => '\0',
+            false => '\n',
+        },
+        sort_key: args.sort_key,
+        numeric: args.numeric,
+        top: args.top,
+        min_count,
+        max_count,
+        percent: args.percent,
+        trim: args.trim,
+        squeeze_space: args.squeeze_space,
+        normalize: args.normalize.map(|form| match form.as_str() {
+            "nfkc" => Form::Nfkc,
+            _ => Form::Nfc,
+        }),
+        approx_top: args.approx_top,
+        capacity: capacity.max(args.approx_top.unwrap_or(0)),
+        cardinality: args.cardinality.then_some(precision),
+        merge: args.merge,
+        weight_idx: args.weight_field.map(|field| field - 1),
+        threads: match args.threads {
+            Some(0) => return Err("--threads must be positive".to_owned()),
+            Some(threads) => threads,
+            None => 1,
+        },
+        stable: args.stable,
+        format: match args.format.as_str() {
+            "line-count" => Format::LineCount,
+            "count" => Format::Count,
+            "line" => Format::Line,
+            _ => Format::CountLine,
+        },
+        ngram: match args.words {
+            true => args.ngram.or(Some(1)),
+            false => args.ngram,
+        },
+        token_regex: args.token_regex.as_deref().map(Regex::new).transpose()?,
+        chars: args.chars,
+        pad: args.pad,
+        json: args.json,
+        csv: args.csv,
+        match_regex: args.match_regex.as_deref().map(Regex::new).transpose()?,
+        exclude_regex: args.exclude_regex.as_deref().map(Regex::new).transpose()?,
+        extract: args.extract.as_deref().map(Regex::new).transpose()?,
+        no_match: args.no_match,
+        prefix: args.prefix,
+        lengths: args.lengths,
+        bytes: args.bytes,
+        distinct_idx,
+        approx: args.approx.then_some(precision),
+        summary: args.summary.map(|to| match to.as_str() {
+            "stderr" => SummaryTo::Stderr,
+            _ => SummaryTo::Stdout,
+        }),
+        sorted: args.sorted.map(|action| match action.as_str() {
+            "warn" => OnUnsorted::Warn,
+            _ => OnUnsorted::Error,
+        }),
+        entropy: args.entropy.map(|when| match when.as_str() {
+            "only" => EntropyWhen::Only,
+            _ => EntropyWhen::After,
+        }),
+        time_idx: args.time_field.map(|field| field - 1),
+        bucket: args.bucket.map(|bucket| match bucket.as_str() {
+            "second" => Bucket::Second,
+            "hour" => Bucket::Hour,
+            "day" => Bucket::Day,
+            _ => Bucket::Minute,
+        }),
+        time_parser: TimeParser::new(args.time_format.as_deref())?,
+        field_idx: args.field.map(|field| field - 1),
+        bin,
+        by_file: args.by_file,
+        external: args.external,
+        tmpdir: args.tmpdir.unwrap_or_else(std::env::temp_dir),
+        max_memory: args.max_memory.unwrap_or(MAX_MEMORY),
+        window: args.window,
+        flush_every: args.flush_every,
+        reset: args.reset,
+        input_files,
+    })
+}
+
+fn run<R: BufRead + Send + 'static>(
+    inputs: Vec<R>,
+    mut ofs: impl Write,
+    program_option: ProgramOption,
+) -> Result<(), String> {
+    if let Some(precision) = program_option.cardinality {
+        let mut sketch = HyperLogLog::new(precision);
+        for_each_counted(concat(inputs), &program_option, |line, _| {
+            sketch.push(&line)
+        })?;
+        let estimate = sketch.estimate().round();
+        write!(ofs, "{}{}", estimate, program_option.record_delim).expect("Error writing");
+        return Ok(());
+    }
+    if let Some(on_unsorted) = program_option.sorted {
+        return count_sorted(concat(inputs), ofs, &program_option, on_unsorted);
+    }
+    if program_option.external {
+        return count_external(concat(inputs), ofs, &program_option);
+    }
+    if program_option.window.is_some() || program_option.flush_every.is_some() {
+        return count_streaming(concat(inputs), ofs, &program_option);
+    }
+    let count_range = program_option.min_count..=program_option.max_count;
+    let mut summary = Summary::default();
+    let mut diversity = Diversity::new(&program_option);
+    let mut per_file = ByFile::new();
+    let total;
+    let mut counts: Vec<(String, usize)>;
+    if let Some(n) = program_option.approx_top {
+        let mut sketch = SpaceSaving::new(program_option.capacity);
+        let mut num_lines = 0;
+        for_each_counted(concat(inputs), &program_option, |line, count| {
+            sketch.push(line, count);
+            num_lines += count;
+        })?;
+        total = num_lines;
+        counts = sketch.into_top(n);
+        counts.retain(|(_, count)| count_range.contains(count));
+    } else {
+        let mut map = match program_option.threads {
+            _ if program_option.by_file => {
+                per_file = count_by_file(inputs, &program_option)?;
+                per_file
+                    .iter()
+                    .map(|(line, (first, counts))| (line.clone(), (*first, counts.iter().sum())))
+                    .collect()
+            }
+            _ if program_option.distinct_idx.is_some() => {
+                count_distinct(concat(inputs), &program_option)?
+            }
+            1 => {
+                let mut map = Counts::new();
+                for_each_counted(concat(inputs), &program_option, |line, count| {
+                    let first = map.len();
+                    map.entry(line).or_insert((first, 0)).1 += count;
+                })?;
+                map
+            }
+            _ => count_threaded(concat(inputs), &program_option)?,
+        };
+        total = map.values().map(|(_, count)| count).sum();
+        if program_option.summary.is_some() || program_option.entropy.is_some() {
+            map.iter().for_each(|(line, (_, count))| {
+                summary.add(line, *count);
+                diversity.add(*count);
+            });
+        }
+        map.retain(|_, (_, count)| count_range.contains(count));
+        let mut entries: Vec<_> = map.into_iter().collect();
+        if program_option.stable {
+            entries.sort_unstable_by_key(|(_, (first, _))| *first);
+        }
+        let entries = entries.into_iter().map(|(line, (_, count))| (line, count));
+        counts = match program_option.top {
+            Some(n) => top_counts(entries, n),
+            None => entries.collect(),
+        };
+    }
+    sort_counts(&mut counts, &program_option);
+    write_counts(&mut ofs, counts, total, per_file, &program_option);
+    summary.write_to(&mut ofs, &program_option);
+    diversity.write_to(ofs, &program_option);
+    Ok(())
+}
+
+// order the counts by line if requested
+fn sort_counts(counts: &mut [(String, usize)], program_option: &ProgramOption) {
+    if program_option.numeric {
+        counts.sort_by(|(a, _), (b, _)| compare_numeric(a, b));
+    } else if program_option.sort_key {
+        counts.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+}
+
+// print the counts with the per-file counts and the percentages as requested
+fn write_counts(
+    ofs: &mut impl Write,
+    counts: impl IntoIterator<Item = (String, usize)>,
+    total: usize,
+    mut per_file: ByFile,
+    program_option: &ProgramOption,
+) {
+    let cumulate = program_option.top.is_some()
+        || program_option.approx_top.is_some()
+        || program_option.sort_key
+        || program_option.stable
+        || program_option.external;
+    let mut cumulative = 0;
+    counts.into_iter().for_each(|(line, count)| {
+        let mut columns = match per_file.remove(&line) {
+            Some((_, counts)) => counts.iter().map(|n| ("count", n.to_string())).collect(),
+            None => vec![],
+        };
+        columns.push(("count", count.to_string()));
+        if program_option.percent {
+            cumulative += count;
+            columns.push(("percent", format!("{:.2}", percentage(count, total))));
+            if cumulate {
+                let cumulative = percentage(cumulative, total);
+                columns.push(("cumulative", format!("{:.2}", cumulative)));
+            }
+        }
+        write_entry(ofs, line, columns, program_option);
+    });
+}
+
+// count with the counts spilled to disk whenever they exceed the memory,
+// and print the merged counts in byte order of the lines
+fn count_external(
+    ifs: impl BufRead,
+    mut ofs: impl Write,
+    program_option: &ProgramOption,
+) -> Result<(), String> {
+    let to_msg = |e: io::Error| format!("temporary file in {:?}: {}", program_option.tmpdir, e);
+    let mut counts = ExternalCounts::new(program_option.max_memory, &program_option.tmpdir);
+    let mut total = 0;
+    let mut counted = Vec::new();
+    for (linenum, line) in records(ifs, program_option.record_delim).enumerate() {
+        let line = line.expect("failed to read");
+        parse_line(linenum, line, program_option, |line, count| {
+            counted.push((line, count))
+        })?;
+        for (line, count) in counted.drain(..) {
+            total += count;
+            counts.push(line, count).map_err(to_msg)?;
+        }
+    }
+    let mut error = None;
+    let mut summary = Summary::default();
+    let mut diversity = Diversity::new(program_option);
+    let count_range = program_option.min_count..=program_option.max_count;
+    let entries = counts
+        .into_sorted()
+        .map_err(to_msg)?
+        .map_while(|entry| entry.map_err(|e| error = Some(e)).ok())
+        .inspect(|(line, count)| {
+            summary.add(line, *count);
+            diversity.add(*count);
+        })
+        .filter(|(_, count)| count_range.contains(count));
+    match program_option.top {
+        Some(n) => {
+            let mut counts = top_counts(entries, n);
+            if program_option.sort_key {
+                counts.sort_by(|(a, _), (b, _)| a.cmp(b));
+            }
+            write_counts(&mut ofs, counts, total, ByFile::new(), program_option)
+        }
+        None => write_counts(&mut ofs, entries, total, ByFile::new(), program_option),
+    }
+    if let Some(e) = error {
+        ofs.flush().expect("Error writing");
+        return Err(to_msg(e));
+    }
+    summary.write_to(&mut ofs, program_option);
+    diversity.write_to(ofs, program_option);
+    Ok(())
+}
+
+#[derive(Default)]
+struct Summary {
+    total: usize,
+    distinct: usize,
+    top: Option<(usize, String)>, // the highest count and the first line in byte order with it
+}
+
+impl Summary {
+    fn add(&mut self, line: &str, count: usize) {
+        self.total += count;
+        self.distinct += 1;
+        let is_top = match &self.top {
+            Some((max, top)) => count > *max || (count == *max && line < top.as_str()),
+            None => true,
+        };
+        if is_top {
+            self.top = Some((count, line.to_owned()));
+        }
+    }
+
+    // print the summary after the lines if requested
+    fn write_to(self, mut ofs: impl Write, program_option: &ProgramOption) {
+        let mut stderr = io::stderr();
+        let (ofs, end): (&mut dyn Write, char) = match program_option.summary {
+            Some(SummaryTo::Stdout) => (&mut ofs, program_option.record_delim),
+            Some(SummaryTo::Stderr) => {
+                ofs.flush().expect("Error writing");
+                (&mut stderr, '\n')
+            }
+            None => return,
+        };
+        let (max, top) = self.top.unwrap_or_default();
+        if program_option.json {
+            write!(
+                ofs,
+                "{{\"total\": {}, \"distinct\": {}, \"max\": {}, \"top\": {}}}{}",
+                self.total,
+                self.distinct,
+                max,
+                json_string(&top),
+                end
+            )
+            .expect("Error writing");
+            return;
+        }
+        let top = match program_option.csv {
+            true => quote_csv(&top, &program_option.delim),
+            false => Cow::Borrowed(top.as_str()),
+        };
+        let delim = &program_option.delim;
+        write!(ofs, "total{}{}{}", delim, self.total, end).expect("Error writing");
+        write!(ofs, "distinct{}{}{}", delim, self.distinct, end).expect("Error writing");
+        write!(ofs, "max{}{}{}", delim, max, end).expect("Error writing");
+        write!(ofs, "top{}{}{}", delim, top, end).expect("Error writing");
+    }
+}
+
+#[test]
+fn test_summary() {
+    let mut summary = Summary::default();
+    for (line, count) in [("b", 3), ("c", 1), ("a", 3)] {
+        summary.add(line, count);
+    }
+    assert_eq!(summary.total, 7);
+    assert_eq!(summary.distinct, 3);
+    assert_eq!(summary.top, Some((3, "a".to_owned())));
+}
+
+// a number of lines, or a number of seconds followed by `s`
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Span {
+    Lines(usize),
+    Time(Duration),
+}
+
+fn parse_span(s: &str) -> Result<Span, String> {
+    let (number, seconds) = match s.strip_suffix('s') {
+        Some(number) => (number, true),
+        None => (s, false),
+    };
+    match number.parse::<usize>() {
+        Ok(0) | Err(_) => {
+            Err("expected a positive number of lines, or of seconds followed by s".to_owned())
+        }
+        Ok(n) if seconds => Ok(Span::Time(Duration::from_secs(n as u64))),
+        Ok(n) => Ok(Span::Lines(n)),
+    }
+}
+
+#[test]
+fn test_parse_span() {
+    assert_eq!(parse_span("100"), Ok(Span::Lines(100)));
+    assert_eq!(parse_span("60s"), Ok(Span::Time(Duration::from_secs(60))));
+    assert!(parse_span("0").is_err());
+    assert!(parse_span("1m").is_err());
+}
+
+// counts of the lines read, or only of those in the window if any,
+// with the time and the lines counted for each line in the window in order;
+// the time is either since the start or that in `--time-field`
+struct StreamCounts {
+    window: Option<Span>,
+    counts: HashMap<String, usize>,
+    total: usize,
+    recent: VecDeque<(Duration, Vec<(String, usize)>)>,
+    latest: Duration, // the latest time of the lines
+}
+
+impl StreamCounts {
+    fn new(window: Option<Span>) -> Self {
+        StreamCounts {
+            window,
+            counts: HashMap::new(),
+            total: 0,
+            recent: VecDeque::new(),
+            latest: Duration::ZERO,
+        }
+    }
+
+    // the lines are evicted in the order they are pushed, so a line whose time is out of order
+    // is evicted along with the lines around it, or skipped if it is already out of the window
+    fn push(&mut self, time: Duration, counted: Vec<(String, usize)>) {
+        if let Some(Span::Time(duration)) = self.window {
+            if self.latest.saturating_sub(time) > duration {
+                return;
+            }
+        }
+        self.latest = self.latest.max(time);
+        for (line, count) in &counted {
+            match self.counts.get_mut(line) {
+                Some(total) => *total += count,
+                None => {
+                    self.counts.insert(line.clone(), *count);
+                }
+            }
+            self.total += count;
+        }
+        if self.window.is_some() {
+            self.recent.push_back((time, counted));
+            self.evict(self.latest);
+        }
+    }
+
+    // forget the lines that have left the window by now
+    fn evict(&mut self, now: Duration) {
+        let window = match self.window {
+            Some(x) => x,
+            None => return,
+        };
+        while let Some((time, _)) = self.recent.front() {
+            let expired = match window {
+                Span::Lines(n) => self.recent.len() > n,
+                Span::Time(duration) => now.saturating_sub(*time) > duration,
+            };
+            if !expired {
+                break;
+            }
+            let (_, counted) = self.recent.pop_front().unwrap();
+            for (line, count) in counted {
+                let total = self.counts.get_mut(&line).unwrap();
+                *total -= count;
+                if *total == 0 {
+                    self.counts.remove(&line);
+                }
+                self.total -= count;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.counts.clear();
+        self.total = 0;
+        self.recent.clear();
+    }
+}
+
+#[test]
+fn test_stream_counts() {
+    let now = Duration::from_secs(100);
+    let mut window = StreamCounts::new(Some(Span::Lines(2)));
+    for line in ["a", "b", "a", "c"] {
+        window.push(now, vec![(line.to_owned(), 1)]);
+    }
+    assert_eq!(window.counts.len(), 2);
+    assert_eq!(window.counts["a"], 1);
+    assert_eq!(window.counts["c"], 1);
+    assert_eq!(window.total, 2);
+
+    let mut window = StreamCounts::new(Some(Span::Time(Duration::from_secs(10))));
+    window.push(now, vec![("a".to_owned(), 1), ("b".to_owned(), 2)]);
+    window.push(now + Duration::from_secs(5), vec![("a".to_owned(), 1)]);
+    window.evict(now + Duration::from_secs(12));
+    assert_eq!(window.counts, HashMap::from([("a".to_owned(), 1)]));
+    assert_eq!(window.total, 1);
+
+    // the times in the lines, e.g., of a log replayed faster than it was written
+    let mut window = StreamCounts::new(Some(Span::Time(Duration::from_secs(60))));
+    for (seconds, line) in [(0, "a"), (30, "b"), (20, "c"), (90, "a"), (10, "d")] {
+        window.push(
+            now + Duration::from_secs(seconds),
+            vec![(line.to_owned(), 1)],
+        );
+    }
+    // the line at 0 is evicted by the one at 90, which also leaves that at 10 out of the window
+    let expected = [("a", 1), ("b", 1), ("c", 1)].map(|(line, count)| (line.to_owned(), count));
+    assert_eq!(window.counts, HashMap::from(expected));
+    assert_eq!(window.total, 3);
+
+    // without a window, the lines are only forgotten when cleared
+    let mut counts = StreamCounts::new(None);
+    counts.push(now, vec![("a".to_owned(), 1)]);
+    counts.evict(now + Duration::from_secs(3600));
+    assert_eq!(counts.total, 1);
+    assert!(counts.recent.is_empty());
+    counts.clear();
+    assert!(counts.counts.is_empty());
+    assert_eq!(counts.total, 0);
+}
+
+// set on SIGUSR1 to print the counts so far
+static PRINT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_print(_: libc::c_int) {
+    PRINT_REQUESTED.store(true, atomic::Ordering::Relaxed);
+}
+
+// how often to check for SIGUSR1 and `--flush-every` while waiting for input
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// count the lines, or those in the window, as they are read, and print the counts
+// on SIGUSR1, every `--flush-every`, and at the end of the input
+fn count_streaming(
+    ifs: impl BufRead + Send + 'static,
+    mut ofs: impl Write,
+    program_option: &ProgramOption,
+) -> Result<(), String> {
+    let handler = request_print as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) };
+    // read in another thread so that the signal and the flushes are handled while waiting for input
+    let record_delim = program_option.record_delim;
+    let (sender, receiver) = mpsc::sync_channel(BATCH_SIZE);
+    thread::spawn(move || {
+        for line in records(ifs, record_delim) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let mut counts = StreamCounts::new(program_option.window);
+    // the time of the lines is that in `--time-field` if any, or else since the start
+    let start = Instant::now();
+    let mut printed = false;
+    let mut pending = false; // whether any line is read since the last printout
+    let mut last_flush = Instant::now();
+    let mut linenum = 0;
+    loop {
+        let mut flush = false;
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => {
+                let mut counted = Vec::new();
+                let line = line.expect("failed to read");
+                let time = match program_option.time_idx {
+                    Some(idx) if is_selected(&line, program_option) => {
+                        let delim = &program_option.delim;
+                        parse_seconds(&line, delim, idx, &program_option.time_parser)
+                            .map_err(|msg| format!("{}: {}", linenum + 1, msg))?
+                    }
+                    _ => start.elapsed(),
+                };
+                parse_line(linenum, line, program_option, |line, count| {
+                    counted.push((line, count))
+                })?;
+                counts.push(time, counted);
+                linenum += 1;
+                pending = true;
+                if let Some(Span::Lines(n)) = program_option.flush_every {
+                    flush = linenum % n == 0;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if let Some(Span::Time(interval)) = program_option.flush_every {
+            flush = last_flush.elapsed() >= interval;
+        }
+        if PRINT_REQUESTED.swap(false, atomic::Ordering::Relaxed) || flush {
+            write_stream(&mut ofs, &mut counts, start, printed, program_option);
+            printed = true;
+            pending = false;
+        }
+        if flush {
+            last_flush = Instant::now();
+            if program_option.reset {
+                counts.clear();
+            }
+        }
+    }
+    if pending || !printed {
+        write_stream(&mut ofs, &mut counts, start, printed, program_option);
+    }
+    Ok(())
+}
+
+// print the counts so far after a line of `#` and the time with `--flush-every`,
+// or else after an empty line unless they are the first printed
+fn write_stream(
+    ofs: &mut impl Write,
+    counts: &mut StreamCounts,
+    start: Instant,
+    printed: bool,
+    program_option: &ProgramOption,
+) {
+    // the lines that have left the window while waiting for input, unless by `--time-field`
+    if program_option.time_idx.is_none() {
+        counts.evict(start.elapsed());
+    }
+    let record_delim = program_option.record_delim;
+    if program_option.flush_every.is_some() {
+        write!(ofs, "# {}{}", time::format_now(), record_delim).expect("Error writing");
+    } else if printed {
+        write!(ofs, "{}", record_delim).expect("Error writing");
+    }
+    let count_range = program_option.min_count..=program_option.max_count;
+    let entries = counts
+        .counts
+        .iter()
+        .filter(|(_, count)| count_range.contains(count))
+        .map(|(line, count)| (line.clone(), *count));
+    let mut entries = match program_option.top {
+        Some(n) => top_counts(entries, n),
+        None => entries.collect(),
+    };
+    sort_counts(&mut entries, program_option);
+    write_counts(ofs, entries, counts.total, ByFile::new(), program_option);
+    ofs.flush().expect("Error writing");
+}
+
+// the counts of the distinct lines, kept only if `--entropy` is set
+struct Diversity(Option<Vec<usize>>);
+
+impl Diversity {
+    fn new(program_option: &ProgramOption) -> Self {
+        Diversity(program_option.entropy.map(|_| Vec::new()))
+    }
+
+    fn add(&mut self, count: usize) {
+        if let Some(counts) = &mut self.0 {
+            counts.push(count);
+        }
+    }
+
+    // Shannon entropy in bits, perplexity, and Gini coefficient of the counts
+    fn statistics(mut counts: Vec<usize>) -> (f64, f64, f64) {
+        let total: usize = counts.iter().sum();
+        if total == 0 {
+            return (0.0, 1.0, 0.0);
+        }
+        let total = total as f64;
+        let entropy: f64 = counts
+            .iter()
+            .map(|&count| count as f64 / total)
+            .map(|p| -p * p.log2())
+            .sum();
+        // with the counts in ascending order, G = 2 sum(i * x_i) / (n sum(x_i)) - (n + 1) / n
+        counts.sort_unstable();
+        let n = counts.len() as f64;
+        let weighted: f64 = (1..).zip(&counts).map(|(i, &x)| (i * x) as f64).sum();
+        let gini = 2.0 * weighted / (n * total) - (n + 1.0) / n;
+        (entropy.max(0.0), entropy.exp2(), gini.max(0.0))
+    }
+
+    // print the statistics after the lines and the summary if requested
+    fn write_to(self, mut ofs: impl Write, program_option: &ProgramOption) {
+        let counts = match self.0 {
+            Some(counts) => counts,
+            None => return,
+        };
+        let (entropy, perplexity, gini) = Self::statistics(counts);
+        let end = program_option.record_delim;
+        if program_option.json {
+            write!(
+                ofs,
+                "{{\"entropy\": {:.4}, \"perplexity\": {:.4}, \"gini\": {:.4}}}{}",
+                entropy, perplexity, gini, end
+            )
+            .expect("Error writing");
+            return;
+        }
+        let delim = &program_option.delim;
+        write!(ofs, "entropy{}{:.4}{}", delim, entropy, end).expect("Error writing");
+        write!(ofs, "perplexity{}{:.4}{}", delim, perplexity, end).expect("Error writing");
+        write!(ofs, "gini{}{:.4}{}", delim, gini, end).expect("Error writing");
+    }
+}
+
+#[test]
+fn test_diversity() {
+    let close = |(a, b, c): (f64, f64, f64), (x, y, z): (f64, f64, f64)| {
+        (a - x).abs() < 1e-9 && (b - y).abs() < 1e-9 && (c - z).abs() < 1e-9
+    };
+    assert!(close(
+        Diversity::statistics(vec![1, 1, 1, 1]),
+        (2.0, 4.0, 0.0)
+    ));
+    assert!(close(Diversity::statistics(vec![4]), (0.0, 1.0, 0.0)));
+    let entropy = -(0.25f64.log2() * 0.25 + 0.75f64.log2() * 0.75);
+    assert!(close(
+        Diversity::statistics(vec![3, 1]),
+        (entropy, entropy.exp2(), 0.25)
+    ));
+    assert!(close(Diversity::statistics(vec![]), (0.0, 1.0, 0.0)));
+}
+
+// count the runs of identical lines and print each as soon as it ends
+fn count_sorted(
+    ifs: impl BufRead,
+    mut ofs: impl Write,
+    program_option: &ProgramOption,
+    on_unsorted: OnUnsorted,
+) -> Result<(), String> {
+    let count_range = program_option.min_count..=program_option.max_count;
+    let mut current = Option::<(String, usize)>::None;
+    let mut summary = Summary::default();
+    let mut diversity = Diversity::new(program_option);
+    let mut warned = false;
+    for (linenum, line) in records(ifs, program_option.record_delim).enumerate() {
+        let mut counted = None;
+        let line = line.expect("failed to read");
+        parse_line(linenum, line, program_option, |line, count| {
+            counted = Some((line, count))
+        })?;
+        let (line, count) = match counted {
+            Some(x) => x,
+            None => continue,
+        };
+        match &mut current {
+            Some((prev, total)) if *prev == line => {
+                *total += count;
+                continue;
+            }
+            Some((prev, _)) if line < *prev => {
+                let msg = format!(
+                    "line {}: `{}` comes before the previous line; the input is not sorted",
+                    linenum + 1,
+                    line
+                );
+                if on_unsorted == OnUnsorted::Error {
+                    ofs.flush().expect("Error writing");
+                    return Err(msg);
+                }
+                if !warned {
+                    eprintln!("warning: {}", msg);
+                    warned = true;
+                }
+            }
+            _ => (),
+        }
+        if let Some((prev, total)) = current.replace((line, count)) {
+            summary.add(&prev, total);
+            diversity.add(total);
+            if count_range.contains(&total) {
+                write_entry(
+                    &mut ofs,
+                    prev,
+                    vec![("count", total.to_string())],
+                    program_option,
+                );
+            }
+        }
+    }
+    if let Some((line, total)) = current {
+        summary.add(&line, total);
+        diversity.add(total);
+        if count_range.contains(&total) {
+            write_entry(
+                &mut ofs,
+                line,
+                vec![("count", total.to_string())],
+                program_option,
+            );
+        }
+    }
+    summary.write_to(&mut ofs, program_option);
+    diversity.write_to(ofs, program_option);
+    Ok(())
+}
+
+// print the named columns of the count and the line in the format
+fn write_entry(
+    ofs: &mut impl Write,
+    line: String,
+    columns: Vec<(&str, String)>,
+    program_option: &ProgramOption,
+) {
+    if program_option.entropy == Some(EntropyWhen::Only) {
+        return;
+    }
+    let delim = &program_option.delim;
+    if program_option.json {
+        let fields: Vec<String> = columns
+            .into_iter()
+            .map(|(name, value)| format!("\"{}\": {}", name, value))
+            .collect();
+        write!(
+            ofs,
+            "{{{}, \"value\": {}}}{}",
+            fields.join(", "),
+            json_string(&line),
+            program_option.record_delim
+        )
+        .expect("Error writing");
+        return;
+    }
+    let line = match program_option.csv {
+        true => quote_csv(&line, delim).into_owned(),
+        false => line,
+    };
+    let mut columns: Vec<String> = columns.into_iter().map(|(_, value)| value).collect();
+    match program_option.format {
+        Format::CountLine => columns.push(line),
+        Format::LineCount => columns.insert(0, line),
+        Format::Count => (),
+        Format::Line => columns = vec![line],
+    }
+    write!(
+        ofs,
+        "{}{}",
+        columns.join(delim),
+        program_option.record_delim
+    )
+    .expect("Error writing")
+}
+
+// pass the line to count, or each of its words with `-w`, along with its count
+fn parse_line(
+    linenum: usize,
+    line: String,
+    program_option: &ProgramOption,
+    mut counted: impl FnMut(String, usize),
+) -> Result<(), String> {
+    if !is_selected(&line, program_option) {
+        return Ok(());
+    }
+    let delim = &program_option.delim;
+    let time_bucket = program_option.time_idx.zip(program_option.bucket);
+    let key = match (time_bucket, program_option.field_idx) {
+        (Some((idx, bucket)), _) => Some(
+            parse_time(&line, delim, idx, bucket, &program_option.time_parser)
+                .map_err(|msg| format!("{}: {}", linenum + 1, msg))?,
+        ),
+        (None, Some(idx)) => Some(
+            // with `--lengths`, the bins are of the lengths instead
+            parse_field(
+                &line,
+                delim,
+                idx,
+                program_option.bin.filter(|_| !program_option.lengths),
+            )
+            .map_err(|msg| format!("{}: {}", linenum + 1, msg))?,
+        ),
+        (None, None) => None,
+    };
+    let (line, count) = match (program_option.merge, program_option.weight_idx) {
+        (true, _) => parse_counted(line, delim).ok_or(format!(
+            "{}: expected a count followed by the delimiter",
+            linenum + 1
+        ))?,
+        (false, Some(idx)) => {
+            parse_weighted(&line, delim, idx).map_err(|msg| format!("{}: {}", linenum + 1, msg))?
+        }
+        (false, None) => (line, 1),
+    };
+    let line = key.unwrap_or(line);
+    let mut line = preprocess(line, program_option);
+    if let Some(regex) = &program_option.extract {
+        line = match (regex.find(&line), &program_option.no_match) {
+            (Some(found), _) => found.to_owned(),
+            (None, Some(sentinel)) => sentinel.clone(),
+            (None, None) => return Ok(()),
+        };
+    }
+    if let Some(n) = program_option.prefix {
+        line.truncate(prefix(&line, n, program_option.bytes).len());
+    }
+    if program_option.lengths {
+        let length = match program_option.bytes {
+            true => line.len(),
+            false => line.chars().count(),
+        };
+        line = match program_option.bin {
+            Some(bin) => lower_bound(length as f64, bin),
+            None => length.to_string(),
+        };
+    }
+    let n = match program_option.ngram {
+        Some(n) => n,
+        None => {
+            if !(program_option.suppress && line.is_empty()) {
+                counted(line, count);
+            }
+            return Ok(());
+        }
+    };
+    let mut tokens = match (program_option.chars, &program_option.token_regex) {
+        (true, _) => line
+            .char_indices()
+            .map(|(i, c)| &line[i..i + c.len_utf8()])
+            .collect(),
+        (false, Some(regex)) => regex.find_all(&line),
+        (false, None) => words(&line).collect(),
+    };
+    if let Some(pad) = &program_option.pad {
+        let pads = std::iter::repeat_n(pad.as_str(), n - 1);
+        tokens = pads.clone().chain(tokens).chain(pads).collect();
+    }
+    let separator = if program_option.chars { "" } else { " " };
+    for ngram in tokens.windows(n) {
+        counted(ngram.join(separator), count);
+    }
+    Ok(())
+}
+
+// whether the line passes `--match` and `--exclude`
+fn is_selected(line: &str, program_option: &ProgramOption) -> bool {
+    let matched = match &program_option.match_regex {
+        Some(regex) => regex.is_match(line),
+        None => true,
+    };
+    let excluded = match &program_option.exclude_regex {
+        Some(regex) => regex.is_match(line),
+        None => false,
+    };
+    matched && !excluded
+}
+
+type Lines<'a> = Box<dyn Iterator<Item = io::Result<String>> + 'a>;
+
+// the records of the input, terminated by newline, or by NUL with `-z`
+fn records<'a>(ifs: impl BufRead + 'a, record_delim: char) -> Lines<'a> {
+    match record_delim {
+        '\0' => Box::new(ifs.split(b'\0').map(|record| {
+            String::from_utf8(record?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })),
+        _ => Box::new(ifs.lines()),
+    }
+}
+
+// the inputs read one after another
+fn concat<R: BufRead + Send + 'static>(inputs: Vec<R>) -> Box<dyn BufRead + Send> {
+    inputs
+        .into_iter()
+        .fold(Box::new(io::empty()), |ifs, input| {
+            Box::new(ifs.chain(input))
+        })
+}
+
+// order of first appearance and count in each input of each line
+type ByFile = HashMap<String, (usize, Vec<usize>)>;
+
+fn count_by_file(
+    inputs: Vec<impl BufRead>,
+    program_option: &ProgramOption,
+) -> Result<ByFile, String> {
+    let num_inputs = inputs.len();
+    let mut per_file = ByFile::new();
+    for (idx, ifs) in inputs.into_iter().enumerate() {
+        for_each_counted(ifs, program_option, |line, count| {
+            let first = per_file.len();
+            let (_, counts) = per_file
+                .entry(line)
+                .or_insert_with(|| (first, vec![0; num_inputs]));
+            counts[idx] += count;
+        })
+        .map_err(|msg| format!("{}: {}", program_option.input_files[idx], msg))?;
+    }
+    Ok(per_file)
+}
+
+// pass each line and its count to counted
+fn for_each_counted(
+    ifs: impl BufRead,
+    program_option: &ProgramOption,
+    mut counted: impl FnMut(String, usize),
+) -> Result<(), String> {
+    for (linenum, line) in records(ifs, program_option.record_delim).enumerate() {
+        let line = line.expect("failed to read");
+        parse_line(linenum, line, program_option, &mut counted)?;
+    }
+    Ok(())
+}
+
+fn words(line: &str) -> impl Iterator<Item = &str> {
+    line.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+}
+
+#[test]
+fn test_words() {
+    assert_eq!(
+        words("Don't  stop_me, 2night!").collect::<Vec<_>>(),
+        ["Don", "t", "stop_me", "2night"]
+    );
+    assert!(words(" ,. ").next().is_none());
+}
+
+// order of first appearance and count of each line
+type Counts = HashMap<String, (usize, usize)>;
+
+// line number, and index in the line of the words, n-grams or matches
+type Position = (usize, usize);
+
+const BATCH_SIZE: usize = 1024;
+
+// count the batches of lines in turns of the threads, and then add up their counts
+fn count_threaded(ifs: impl BufRead, program_option: &ProgramOption) -> Result<Counts, String> {
+    let threads = program_option.threads;
+    thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..threads)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel::<Vec<(usize, String)>>(threads);
+                let worker = scope.spawn(move || {
+                    let mut map = HashMap::<String, (Position, usize)>::new();
+                    for batch in receiver {
+                        for (linenum, line) in batch {
+                            let mut idx = 0;
+                            parse_line(linenum, line, program_option, |line, count| {
+                                map.entry(line).or_insert(((linenum, idx), 0)).1 += count;
+                                idx += 1;
+                            })?;
+                        }
+                    }
+                    Ok(map)
+                });
+                (sender, worker)
+            })
+            .unzip();
+
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut turn = 0;
+        for (linenum, line) in records(ifs, program_option.record_delim).enumerate() {
+            batch.push((linenum, line.expect("failed to read")));
+            if batch.len() >= BATCH_SIZE {
+                let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                if senders[turn].send(full).is_err() {
+                    break; // the worker failed; its error is returned below
+                }
+                turn = (turn + 1) % threads;
+            }
+        }
+        let _ = senders[turn].send(batch);
+        drop(senders);
+
+        let mut maps = workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        // add up into the largest map
+        maps.sort_by_key(|map| Reverse(map.len()));
+        let mut maps = maps.into_iter();
+        let mut merged = maps.next().unwrap_or_default();
+        for map in maps {
+            for (line, (first, count)) in map {
+                let entry = merged.entry(line).or_insert((first, 0));
+                entry.0 = entry.0.min(first);
+                entry.1 += count;
+            }
+        }
+        // number the lines in the order of their positions
+        let mut entries: Vec<_> = merged.into_iter().collect();
+        if program_option.stable {
+            entries.sort_unstable_by_key(|(_, (position, _))| *position);
+        }
+        Ok(entries
+            .into_iter()
+            .enumerate()
+            .map(|(first, (line, (_, count)))| (line, (first, count)))
+            .collect())
+    })
+}
+
+// split the line printed by count into the line and its count
+fn parse_counted(line: String, delim: &str) -> Option<(String, usize)> {
+    let (count, rest) = line.split_once(delim)?;
+    Some((rest.to_owned(), count.trim().parse().ok()?))
+}
+
+#[test]
+fn test_parse_counted() {
+    let parse = |line: &str| parse_counted(line.to_owned(), "\t");
+    assert_eq!(parse("3\ta\tb"), Some(("a\tb".to_owned(), 3)));
+    assert_eq!(parse("3\t"), Some(("".to_owned(), 3)));
+    assert_eq!(parse("3"), None);
+    assert_eq!(parse("x\ta"), None);
+}
+
+// 0-index of the fields A and B given as A,B
+fn parse_field_pair(s: &str) -> Result<(usize, usize), String> {
+    let err = || "expected A,B, where A and B are 1 or greater".to_owned();
+    let (a, b) = s.split_once(',').ok_or_else(err)?;
+    match (a.parse::<usize>(), b.parse::<usize>()) {
+        (Ok(a), Ok(b)) if a > 0 && b > 0 => Ok((a - 1, b - 1)),
+        _ => Err(err()),
+    }
+}
+
+#[test]
+fn test_parse_field_pair() {
+    assert_eq!(parse_field_pair("1,3"), Ok((0, 2)));
+    assert!(parse_field_pair("0,3").is_err());
+    assert!(parse_field_pair("1").is_err());
+    assert!(parse_field_pair("a,b").is_err());
+}
+
+// distinct values of a field, either kept or estimated
+enum Distinct {
+    Exact(HashSet<String>),
+    Approx(HyperLogLog),
+}
+
+impl Distinct {
+    fn insert(&mut self, value: &str) {
+        match self {
+            Distinct::Exact(values) => {
+                if !values.contains(value) {
+                    values.insert(value.to_owned());
+                }
+            }
+            Distinct::Approx(sketch) => sketch.push(value),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Distinct::Exact(values) => values.len(),
+            Distinct::Approx(sketch) => sketch.estimate().round() as usize,
+        }
+    }
+}
+
+// count the distinct values of field B for each value of field A
+fn count_distinct(ifs: impl BufRead, program_option: &ProgramOption) -> Result<Counts, String> {
+    let (key_idx, value_idx) = program_option.distinct_idx.unwrap();
+    let mut distinct = HashMap::<String, (usize, Distinct)>::new();
+    for (linenum, line) in records(ifs, program_option.record_delim).enumerate() {
+        let line = line.expect("failed to read");
+        if !is_selected(&line, program_option) {
+            continue;
+        }
+        let line = preprocess(line, program_option);
+        if program_option.suppress && line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(&program_option.delim).collect();
+        let (key, value) = match (fields.get(key_idx), fields.get(value_idx)) {
+            (Some(key), Some(value)) => (key, value),
+            _ => {
+                let idx = key_idx.max(value_idx);
+                return Err(format!("{}: col {} does not exist", linenum + 1, idx + 1));
+            }
+        };
+        if !distinct.contains_key(*key) {
+            let values = match program_option.approx {
+                Some(precision) => Distinct::Approx(HyperLogLog::new(precision)),
+                None => Distinct::Exact(HashSet::new()),
+            };
+            distinct.insert(key.to_string(), (linenum, values));
+        }
+        distinct.get_mut(*key).unwrap().1.insert(value);
+    }
+    Ok(distinct
+        .into_iter()
+        .map(|(key, (first, values))| (key, (first, values.len())))
+        .collect())
+}
+
+// split the line into the rest of the fields and the weight in the given field
+fn parse_weighted(line: &str, delim: &str, idx: usize) -> Result<(String, usize), String> {
+    let mut fields: Vec<&str> = line.split(delim).collect();
+    if idx >= fields.len() {
+        return Err(format!("col {} does not exist", idx + 1));
+    }
+    let weight = fields.remove(idx);
+    match weight.trim().parse() {
+        Ok(weight) => Ok((fields.join(delim), weight)),
+        Err(_) => Err(format!("cannot parse `{}` as a weight", weight)),
+    }
+}
+
+#[test]
+fn test_parse_weighted() {
+    assert_eq!(
+        parse_weighted("a\t3\tb", "\t", 1),
+        Ok(("a\tb".to_owned(), 3))
+    );
+    assert_eq!(
+        parse_weighted("a\t3", "\t", 0),
+        Err("cannot parse `a` as a weight".to_owned())
+    );
+    assert!(parse_weighted("a\t3", "\t", 2).is_err());
+    assert!(parse_weighted("a\t-3", "\t", 1).is_err());
+}
+
+// the time in the field of the line, truncated to the bucket
+fn parse_time(
+    line: &str,
+    delim: &str,
+    idx: usize,
+    bucket: Bucket,
+    parser: &TimeParser,
+) -> Result<String, String> {
+    let time = match line.split(delim).nth(idx) {
+        Some(x) => x,
+        None => return Err(format!("col {} does not exist", idx + 1)),
+    };
+    parser
+        .bucket(time, bucket)
+        .ok_or_else(|| format!("cannot parse `{}` as a time", time))
+}
+
+// the time in the field of the line, as the duration since the Unix epoch
+fn parse_seconds(
+    line: &str,
+    delim: &str,
+    idx: usize,
+    parser: &TimeParser,
+) -> Result<Duration, String> {
+    let time = match line.split(delim).nth(idx) {
+        Some(x) => x,
+        None => return Err(format!("col {} does not exist", idx + 1)),
+    };
+    match parser.seconds(time) {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err(format!("cannot parse `{}` as a time", time)),
+    }
+}
+
+#[test]
+fn test_parse_time() {
+    let parser = TimeParser::new(None).unwrap();
+    let line = "GET\t2024-05-01T12:34:56\t512";
+    assert_eq!(
+        parse_time(line, "\t", 1, Bucket::Hour, &parser),
+        Ok("2024-05-01T12".to_owned())
+    );
+    assert!(parse_time(line, "\t", 0, Bucket::Hour, &parser).is_err());
+    assert!(parse_time(line, "\t", 3, Bucket::Hour, &parser).is_err());
+}
+
+// the first n characters of the line, or n bytes rounded down to a character boundary
+fn prefix(line: &str, n: usize, bytes: bool) -> &str {
+    if bytes {
+        let end = (0..=n.min(line.len()))
+            .rev()
+            .find(|&i| line.is_char_boundary(i))
+            .unwrap_or(0);
+        return &line[..end];
+    }
+    match line.char_indices().nth(n) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    }
+}
+
+#[test]
+fn test_prefix() {
+    assert_eq!(prefix("10.1.2.3", 4, false), "10.1");
+    assert_eq!(prefix("10", 4, false), "10");
+    assert_eq!(prefix("caf\u{e9}s", 4, false), "caf\u{e9}");
+    assert_eq!(prefix("caf\u{e9}s", 4, true), "caf");
+    assert_eq!(prefix("caf\u{e9}s", 5, true), "caf\u{e9}");
+    assert_eq!(prefix("ab", 9, true), "ab");
+}
+
+// the field of the line, or the lower bound of the bin of its value
+fn parse_field(
+    line: &str,
+    delim: &str,
+    idx: usize,
+    bin: Option<(f64, usize)>,
+) -> Result<String, String> {
+    let field = match line.split(delim).nth(idx) {
+        Some(x) => x,
+        None => return Err(format!("col {} does not exist", idx + 1)),
+    };
+    let bin = match bin {
+        Some(x) => x,
+        None => return Ok(field.to_owned()),
+    };
+    match field.trim().parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(lower_bound(x, bin)),
+        _ => Err(format!("cannot parse `{}` as a number", field)),
+    }
+}
+
+// the lower bound of the bin of the value, with as many decimal places as the width
+fn lower_bound(value: f64, (width, decimals): (f64, usize)) -> String {
+    // tolerate the rounding error of the division, e.g., 0.3 / 0.1 = 2.9999999999999996
+    let lower = (value / width + 1e-9).floor() * width + 0.0; // no -0
+    format!("{:.*}", decimals, lower)
+}
+
+#[test]
+fn test_parse_field() {
+    let line = "/a\t12.5\t-3";
+    assert_eq!(parse_field(line, "\t", 0, None), Ok("/a".to_owned()));
+    assert_eq!(
+        parse_field(line, "\t", 1, Some((10.0, 0))),
+        Ok("10".to_owned())
+    );
+    assert_eq!(
+        parse_field(line, "\t", 2, Some((10.0, 0))),
+        Ok("-10".to_owned())
+    );
+    assert_eq!(
+        parse_field("0.3", "\t", 0, Some((0.1, 1))),
+        Ok("0.3".to_owned())
+    );
+    assert_eq!(
+        parse_field("-0.5", "\t", 0, Some((1.0, 0))),
+        Ok("-1".to_owned())
+    );
+    assert!(parse_field(line, "\t", 0, Some((10.0, 0))).is_err());
+    assert!(parse_field(line, "\t", 3, None).is_err());
+}
+
+// normalize the line as requested before counting
+fn preprocess(mut line: String, program_option: &ProgramOption) -> String {
+    if let Some(form) = program_option.normalize {
+        line = normalize(&line, form);
+    }
+    if program_option.trim {
+        line = line.trim().to_owned();
+    }
+    if program_option.squeeze_space {
+        line = squeeze_space(&line);
+    }
+    line
+}
+
+fn squeeze_space(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    for c in line.chars() {
+        if c != ' ' || !result.ends_with(' ') {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[test]
+fn test_squeeze_space() {
+    assert_eq!(squeeze_space("  a   b\t\tc "), " a b\t\tc ");
+    assert_eq!(squeeze_space(""), "");
+}
+
+fn percentage(count: usize, total: usize) -> f64 {
+    100.0 * count as f64 / total as f64
+}
+
+// the n most frequent lines in descending order of their counts, and then in byte order
+// of the lines so that the ties are always the same, with a heap of at most n
+fn top_counts(counts: impl IntoIterator<Item = (String, usize)>, n: usize) -> Vec<(String, usize)> {
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    for (line, count) in counts {
+        heap.push(Reverse((count, Reverse(line))));
+        if heap.len() > n {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((count, Reverse(line)))| (line, count))
+        .collect()
+}
+
+#[test]
+fn test_top_counts() {
+    let map: HashMap<String, usize> = [("a", 1), ("b", 3), ("c", 2)]
+        .map(|(line, count)| (line.to_owned(), count))
+        .into();
+    assert_eq!(
+        top_counts(map.clone(), 2),
+        [("b".to_owned(), 3), ("c".to_owned(), 2)]
+    );
+    assert!(top_counts(map, 0).is_empty());
+    // ties in byte order, whatever the order of the input
+    let ties = ["d", "b", "a", "c"].map(|line| (line.to_owned(), 1));
+    assert_eq!(
+        top_counts(ties, 2),
+        [("a".to_owned(), 1), ("b".to_owned(), 1)]
+    );
+}
+
+fn main() -> ExitCode {
+    let program_option = match parse_arguments() {
+        Err(ref msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::FAILURE;
+        }
+        Ok(x) => x,
+    };
+
+    let output_file = "/dev/stdout".to_owned();
+
+    let inputs = program_option
+        .input_files
+        .iter()
+        .map(|input| BufReader::new(File::open(input).expect("Error reading input file")))
+        .collect();
+    let ofs = BufWriter::new(File::create(output_file).expect("Error writing to stdout"));
+
+    match run(inputs, ofs, program_option) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Form {
+    Nfc,
+    Nfkc,
+}
+
+/// Normalize the line into the given form.
+pub fn normalize(line: &str, form: Form) -> String {
+    if line.is_ascii() {
+        return line.to_owned();
+    }
+    match form {
+        Form::Nfc => line.nfc().collect(),
+        Form::Nfkc => line.nfkc().collect(),
+    }
+}
+
+#[test]
+fn test_normalize() {
+    // e + combining acute, and the precomposed é
+    assert_eq!(normalize("e\u{301}", Form::Nfc), "\u{e9}");
+    assert_eq!(normalize("\u{e9}", Form::Nfc), "\u{e9}");
+    // combining marks are reordered: dot below (220) before dot above (230)
+    assert_eq!(normalize("s\u{307}\u{323}", Form::Nfc), "\u{1e69}");
+    // Hangul jamo compose into a syllable
+    assert_eq!(normalize("\u{1100}\u{1161}\u{11a8}", Form::Nfc), "\u{ac01}");
+    // compatibility characters are only folded by NFKC
+    assert_eq!(normalize("\u{fb01}\u{2460}", Form::Nfc), "\u{fb01}\u{2460}");
+    assert_eq!(normalize("\u{fb01}\u{2460}", Form::Nfkc), "fi1");
+    // singletons such as the Angstrom sign are never recomposed
+    assert_eq!(normalize("\u{212b}", Form::Nfc), "\u{c5}");
+}
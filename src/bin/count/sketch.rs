@@ -0,0 +1,132 @@
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Space-Saving summary of the most frequent lines with a fixed number of counters.
+/// Once the counters are full, a new line takes over the counter of the least frequent one,
+/// so every count may be overestimated by up to the total number of lines over the capacity.
+pub struct SpaceSaving {
+    capacity: usize,
+    counts: HashMap<String, usize>,
+    order: BTreeSet<(usize, Reverse<String>)>, // the lines of equal counts in reverse byte order
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::with_capacity(capacity),
+            order: BTreeSet::new(),
+        }
+    }
+
+    /// count the line the given number of times
+    pub fn push(&mut self, line: String, weight: usize) {
+        if let Some(count) = self.counts.get_mut(&line) {
+            let mut entry = self.order.take(&(*count, Reverse(line))).unwrap();
+            *count += weight;
+            entry.0 = *count;
+            self.order.insert(entry);
+            return;
+        }
+        let count = match self.counts.len() < self.capacity {
+            true => weight,
+            false => {
+                let (min, Reverse(evicted)) = self.order.pop_first().unwrap();
+                self.counts.remove(&evicted);
+                min + weight
+            }
+        };
+        self.counts.insert(line.clone(), count);
+        self.order.insert((count, Reverse(line)));
+    }
+
+    /// the n lines with the highest estimated counts in descending order of the counts,
+    /// and then in byte order of the lines
+    pub fn into_top(self, n: usize) -> Vec<(String, usize)> {
+        self.order
+            .into_iter()
+            .rev()
+            .take(n)
+            .map(|(count, Reverse(line))| (line, count))
+            .collect()
+    }
+}
+
+#[test]
+fn test_space_saving() {
+    let mut sketch = SpaceSaving::new(2);
+    for line in ["a", "a", "b", "c", "a"] {
+        sketch.push(line.to_owned(), 1);
+    }
+    // c took over the counter of b
+    assert_eq!(
+        sketch.into_top(3),
+        [("a".to_owned(), 3), ("c".to_owned(), 2)]
+    );
+    // ties in byte order
+    let mut sketch = SpaceSaving::new(3);
+    for line in ["c", "b", "a"] {
+        sketch.push(line.to_owned(), 1);
+    }
+    assert_eq!(
+        sketch.into_top(2),
+        [("a".to_owned(), 1), ("b".to_owned(), 1)]
+    );
+}
+
+/// HyperLogLog estimate of the number of distinct lines with 2^precision registers of a byte
+/// each; the standard error is about 1.04 / sqrt(2^precision).
+pub struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u32) -> Self {
+        Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn push(&mut self, line: &str) {
+        let mut hasher = DefaultHasher::new();
+        line.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - self.precision)) as usize;
+        // position of the first 1 bit in the rest of the hash
+        let rank = ((hash << self.precision).leading_zeros() + 1).min(65 - self.precision) as u8;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| (-(r as f64)).exp2()).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        match estimate <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small cardinalities
+            true => m * (m / zeros as f64).ln(),
+            false => estimate,
+        }
+    }
+}
+
+#[test]
+fn test_hyper_log_log() {
+    let mut sketch = HyperLogLog::new(12);
+    assert_eq!(sketch.estimate(), 0.0);
+    for i in 0..100000 {
+        sketch.push(&(i % 50000).to_string());
+    }
+    let error = (sketch.estimate() - 50000.0).abs() / 50000.0;
+    assert!(error < 0.05, "error {}", error);
+}
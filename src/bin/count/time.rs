@@ -0,0 +1,130 @@
+use std::ffi::CString;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Second,
+    Minute,
+    Hour,
+    Day,
+}
+
+// formats of the timestamps parsed unless a format is given, tried in order
+const DEFAULT_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"];
+
+// timestamps parsed by strptime of libc
+pub struct TimeParser {
+    formats: Vec<CString>,
+    unix: bool, // also parse Unix time
+}
+
+impl TimeParser {
+    // parse by the strptime format, or by default as Unix time in seconds
+    // or as ISO 8601 date and time, e.g., 2024-05-01T12:00:05
+    pub fn new(format: Option<&str>) -> Result<Self, String> {
+        let formats = match format {
+            Some(format) => vec![format],
+            None => DEFAULT_FORMATS.to_vec(),
+        };
+        let formats = formats
+            .into_iter()
+            .map(|format| CString::new(format).map_err(|_| "the time format contains NUL"))
+            .collect::<Result<_, _>>()?;
+        Ok(TimeParser {
+            formats,
+            unix: format.is_none(),
+        })
+    }
+
+    // the time truncated to the bucket, or none if the time cannot be parsed;
+    // anything after the time is ignored
+    pub fn bucket(&self, time: &str, bucket: Bucket) -> Option<String> {
+        let tm = self.parse(time)?;
+        Some(format_tm(&tm, bucket))
+    }
+
+    // seconds since the Unix epoch, or none if the time cannot be parsed or precedes the epoch
+    pub fn seconds(&self, time: &str) -> Option<u64> {
+        let mut tm = self.parse(time)?;
+        let seconds = unsafe { libc::timegm(&mut tm) };
+        u64::try_from(seconds).ok()
+    }
+
+    fn parse(&self, time: &str) -> Option<libc::tm> {
+        let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+        if self.unix {
+            // Unix time in UTC, possibly with a fraction of a second
+            if let Some(seconds) = time.parse::<f64>().ok().filter(|x| x.is_finite()) {
+                let seconds = seconds.floor() as libc::time_t;
+                let result = unsafe { libc::gmtime_r(&seconds, &mut tm) };
+                return (!result.is_null()).then_some(tm);
+            }
+        }
+        let c_time = CString::new(time).ok()?;
+        self.formats.iter().find_map(|format| {
+            tm = unsafe { std::mem::zeroed::<libc::tm>() };
+            let rest = unsafe { libc::strptime(c_time.as_ptr(), format.as_ptr(), &mut tm) };
+            (!rest.is_null()).then_some(tm)
+        })
+    }
+}
+
+// the time truncated to the bucket, formatted so that the byte order is the time order
+fn format_tm(tm: &libc::tm, bucket: Bucket) -> String {
+    let date = format!(
+        "{:04}-{:02}-{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday
+    );
+    match bucket {
+        Bucket::Day => date,
+        Bucket::Hour => format!("{}T{:02}", date, tm.tm_hour),
+        Bucket::Minute => format!("{}T{:02}:{:02}", date, tm.tm_hour, tm.tm_min),
+        Bucket::Second => format!(
+            "{}T{:02}:{:02}:{:02}",
+            date, tm.tm_hour, tm.tm_min, tm.tm_sec
+        ),
+    }
+}
+
+// the current time in UTC, e.g., 2024-05-01T12:00:05Z
+pub fn format_now() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    let seconds = now.map_or(0, |x| x.as_secs()) as libc::time_t;
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    unsafe { libc::gmtime_r(&seconds, &mut tm) };
+    format!("{}Z", format_tm(&tm, Bucket::Second))
+}
+
+#[test]
+fn test_time_parser() {
+    let parser = TimeParser::new(None).unwrap();
+    let time = "2024-05-01T12:34:56";
+    assert_eq!(parser.bucket(time, Bucket::Second).unwrap(), time);
+    assert_eq!(
+        parser.bucket(time, Bucket::Minute).unwrap(),
+        "2024-05-01T12:34"
+    );
+    assert_eq!(parser.bucket(time, Bucket::Hour).unwrap(), "2024-05-01T12");
+    assert_eq!(parser.bucket(time, Bucket::Day).unwrap(), "2024-05-01");
+    assert_eq!(
+        parser
+            .bucket("2024-05-01 12:34:56.789", Bucket::Second)
+            .unwrap(),
+        time
+    );
+    assert_eq!(parser.bucket("1714566896.5", Bucket::Second).unwrap(), time);
+    assert_eq!(parser.bucket("yesterday", Bucket::Day), None);
+    assert_eq!(parser.seconds(time), Some(1714566896));
+    assert_eq!(parser.seconds("1714566896.5"), Some(1714566896));
+
+    let parser = TimeParser::new(Some("[%d/%b/%Y:%H:%M:%S")).unwrap();
+    assert_eq!(
+        parser
+            .bucket("[01/May/2024:12:34:56 +0000]", Bucket::Hour)
+            .unwrap(),
+        "2024-05-01T12"
+    );
+    assert_eq!(parser.bucket("2024-05-01T12:34:56", Bucket::Hour), None);
+}
@@ -0,0 +1,335 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+use clap::Parser;
+use suputils::parse_size;
+
+#[derive(Parser)]
+#[command(name = "dedup")]
+#[command(author = "TechHara")]
+#[command(version = "0.1.0")]
+#[command(
+    about = "Print the lines without the duplicates, in the order they appear.
+Unlike `sort -u`, the input does not need to be sorted and the order is kept.
+
+    $ cat input
+    b	1
+    a	2
+    b	1
+    c	3
+    a	4
+
+    $ dedup input
+    b	1
+    a	2
+    c	3
+    a	4
+
+    # set `-k N` to compare only the Nth field
+    $ dedup -k 1 input
+    b	1
+    a	2
+    c	3
+
+    # set `--keep last` to print the last line of each key instead of the first,
+    # in the order of the last lines
+    $ dedup -k 1 --keep last input
+    b	1
+    c	3
+    a	4
+
+    # set `--approx` to remember the keys in a Bloom filter of `--max-memory` instead,
+    # for huge input with too many keys to fit in memory; a line whose key has not appeared
+    # is dropped with a small probability, about 1% while there are fewer keys than
+    # one per 10 bits of the filter, e.g., 200M keys in 256M
+    $ dedup -k 1 --approx --max-memory 1G huge-input
+"
+)]
+struct Arguments {
+    /// Field delimiter character
+    #[arg(short = 't', default_value_t = '\t')]
+    field_delim: char,
+    /// compare only this field instead of the whole line
+    #[arg(short = 'k', value_name = "N")]
+    key_field: Option<usize>,
+    /// which line of each key to print
+    #[arg(long, value_name = "WHICH", default_value = "first", value_parser = ["first", "last"])]
+    keep: String,
+    /// remember the keys in a Bloom filter of `--max-memory`, which may drop a few unique lines
+    #[arg(long, default_value_t = false)]
+    approx: bool,
+    /// memory for the keys, beyond which it fails unless `--approx` is set, e.g., 512M or 4G;
+    /// with `--approx`, the size of the filter [default: 256M]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// Input file; If omitted, read from stdin
+    input: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Keep {
+    First,
+    Last,
+}
+
+struct ProgramOption {
+    field_delim: String,
+    key_idx: Option<usize>, // 0-index
+    keep: Keep,
+    approx: bool,
+    max_memory: Option<usize>,
+    input_file: String,
+}
+
+fn parse_arguments() -> Result<ProgramOption, String> {
+    let args = Arguments::parse();
+    let input_file = match args.input.is_some() && args.input != Some("-".to_owned()) {
+        true => args.input.unwrap(),
+        false => "/dev/stdin".to_owned(),
+    };
+
+    if args.key_field == Some(0) {
+        return Err("key field must be 1 or greater".to_owned());
+    }
+    let keep = match args.keep.as_str() {
+        "first" => Keep::First,
+        "last" => Keep::Last,
+        _ => unreachable!(),
+    };
+    if args.approx && keep == Keep::Last {
+        return Err("--approx cannot keep the last lines".to_owned());
+    }
+
+    Ok(ProgramOption {
+        field_delim: args.field_delim.to_string(),
+        key_idx: args.key_field.map(|field| field - 1),
+        keep,
+        approx: args.approx,
+        max_memory: match args.approx {
+            true => Some(args.max_memory.unwrap_or(MAX_MEMORY)),
+            false => args.max_memory,
+        },
+        input_file,
+    })
+}
+
+// size of the filter with `--approx` unless `--max-memory` is set
+const MAX_MEMORY: usize = 256 << 20;
+
+// number of bits set for each key, which keeps the false positive rate below 1%
+// as long as there are 10 bits or more for each key
+const NUM_HASHES: u64 = 7;
+
+// keys that may have been inserted, with false positives but no false negatives
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn new(bytes: usize) -> Self {
+        BloomFilter {
+            bits: vec![0; (bytes / 8).max(1)],
+        }
+    }
+
+    // insert the key, and return whether it may have been inserted before
+    fn insert(&mut self, key: &str) -> bool {
+        // the bit positions are derived from two hashes by double hashing
+        let hash = |seed: u64| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+        let (h1, h2) = (hash(0), hash(1) | 1);
+        let num_bits = self.bits.len() as u64 * 64;
+        let mut found = true;
+        for i in 0..NUM_HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            found &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+        found
+    }
+}
+
+#[test]
+fn test_bloom_filter() {
+    // 10 bits for each key
+    let mut filter = BloomFilter::new(1250);
+    // a new key is rarely taken to have been inserted
+    let false_positives = (0..1000)
+        .filter(|x| filter.insert(&format!("key{}", x)))
+        .count();
+    assert!(false_positives < 10, "{}", false_positives);
+    // but an inserted key always is
+    for x in 0..1000 {
+        assert!(filter.insert(&format!("key{}", x)));
+    }
+}
+
+// the key of the line, or none if it lacks the key field
+fn key<'a>(line: &'a str, program_option: &ProgramOption) -> Option<&'a str> {
+    match program_option.key_idx {
+        Some(idx) => line.split(&program_option.field_delim).nth(idx),
+        None => Some(line),
+    }
+}
+
+// the keys seen so far
+enum Seen {
+    Exact {
+        keys: HashSet<String>,
+        memory: usize,
+    },
+    Approx(BloomFilter),
+}
+
+impl Seen {
+    fn new(program_option: &ProgramOption) -> Self {
+        match program_option.approx {
+            true => Seen::Approx(BloomFilter::new(program_option.max_memory.unwrap())),
+            false => Seen::Exact {
+                keys: HashSet::new(),
+                memory: 0,
+            },
+        }
+    }
+
+    // insert the key, and return whether it has been seen before
+    fn insert(&mut self, key: &str, max_memory: Option<usize>) -> Result<bool, String> {
+        match self {
+            Seen::Exact { keys, memory } => {
+                if keys.contains(key) {
+                    return Ok(true);
+                }
+                *memory += key.len() + std::mem::size_of::<String>();
+                if max_memory.is_some_and(|max| *memory > max) {
+                    return Err(memory_error(max_memory.unwrap()));
+                }
+                keys.insert(key.to_owned());
+                Ok(false)
+            }
+            Seen::Approx(filter) => Ok(filter.insert(key)),
+        }
+    }
+}
+
+fn memory_error(max_memory: usize) -> String {
+    format!(
+        "the keys take more than {} bytes of `--max-memory`; set `--approx` to remember them approximately",
+        max_memory
+    )
+}
+
+fn run(
+    ifs: impl BufRead,
+    mut ofs: impl Write,
+    program_option: &ProgramOption,
+) -> Result<(), String> {
+    let mut seen = Seen::new(program_option);
+    // with `--keep last`, the last line number and line of each key
+    let mut last = HashMap::<String, (usize, String)>::new();
+    let mut memory = 0;
+    for (linenum, line) in ifs.lines().enumerate() {
+        let line = line.expect("failed to read");
+        let key = match key(&line, program_option) {
+            Some(x) => x,
+            None => {
+                let idx = program_option.key_idx.unwrap();
+                eprintln!("{}: col {} does not exist; skipping", linenum + 1, idx + 1);
+                continue;
+            }
+        };
+        match program_option.keep {
+            Keep::First => {
+                if !seen.insert(key, program_option.max_memory)? {
+                    writeln!(ofs, "{}", line).expect("failed writing out");
+                }
+            }
+            Keep::Last => {
+                if let Some(entry) = last.get_mut(key) {
+                    memory = memory - entry.1.len() + line.len();
+                    *entry = (linenum, line);
+                } else {
+                    memory += 2 * key.len() + line.len() + std::mem::size_of::<(String, String)>();
+                    last.insert(key.to_owned(), (linenum, line));
+                }
+                if program_option.max_memory.is_some_and(|max| memory > max) {
+                    return Err(memory_error(program_option.max_memory.unwrap()));
+                }
+            }
+        }
+    }
+
+    let mut lines: Vec<(usize, String)> = last.into_values().collect();
+    lines.sort_unstable_by_key(|(linenum, _)| *linenum);
+    for (_, line) in lines {
+        writeln!(ofs, "{}", line).expect("failed writing out");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run() {
+    let input = "b\t1\na\t2\nb\t3\nc\t4\na\t5\nb\t3\n";
+    let dedup = |key_idx, keep, approx, max_memory| {
+        let program_option = ProgramOption {
+            field_delim: "\t".to_owned(),
+            key_idx,
+            keep,
+            approx,
+            max_memory,
+            input_file: String::new(),
+        };
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output, &program_option)
+            .map(|_| String::from_utf8(output).unwrap())
+    };
+    let expected = "b\t1\na\t2\nb\t3\nc\t4\na\t5\n";
+    assert_eq!(dedup(None, Keep::First, false, None).unwrap(), expected);
+    assert_eq!(
+        dedup(None, Keep::First, true, Some(1024)).unwrap(),
+        expected
+    );
+    assert_eq!(
+        dedup(Some(0), Keep::First, false, None).unwrap(),
+        "b\t1\na\t2\nc\t4\n"
+    );
+    assert_eq!(
+        dedup(Some(0), Keep::Last, false, None).unwrap(),
+        "c\t4\na\t5\nb\t3\n"
+    );
+    assert!(dedup(None, Keep::First, false, Some(100)).is_err());
+}
+
+fn main() -> ExitCode {
+    let program_option = match parse_arguments() {
+        Err(ref msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::FAILURE;
+        }
+        Ok(x) => x,
+    };
+
+    let ifs = match File::open(&program_option.input_file) {
+        Ok(x) => BufReader::new(x),
+        Err(e) => {
+            eprintln!("{}: {}", program_option.input_file, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let ofs = BufWriter::new(File::create("/dev/stdout").expect("Error writing to stdout"));
+
+    match run(ifs, ofs, &program_option) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
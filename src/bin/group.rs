@@ -1,8 +1,19 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{mpsc, Mutex};
+use std::thread;
 
 use clap::Parser;
+use suputils::random::Rng;
+use suputils::regex::Regex;
+use suputils::{compare_numeric, json_string, parse_size, quote_csv, temp_file};
 
 #[derive(Parser)]
 #[command(name = "group")]
@@ -40,6 +51,31 @@ By default, it assumes the input is sorted by the first field.
     1   a,c,a
     2   b
 
+    # set `--sort-keys` to print the groups ordered by key, with `--numeric` for numeric keys
+    $ group -m --sort-keys input
+    1   a,c,a
+    2   b
+
+    # or set `--stable` to print them in the order the keys first appear
+    $ group -m --stable input
+    1   a,c,a
+    2   b
+
+    # set `--external` for unsorted input too large for `-m`; it is sorted by key
+    # in runs of `--max-memory` that are spilled to `--tmpdir`, and the output is sorted by key
+    $ group --external --max-memory 4G --tmpdir /scratch huge-input
+
+    # set `--check-sorted` to fail on input that is not sorted, or
+    # `--check-sorted=fallback` to group it with the hashmap instead
+    $ group --check-sorted input
+    1   a
+    line 3: key `1` reappears after other keys; the input is not sorted; set `-m` for unsorted input
+
+    # set `--json` to print each group as a JSON object
+    $ group -m --json input
+    {\"key\": \"1\", \"values\": [\"a\",\"c\",\"a\"], \"count\": 3}
+    {\"key\": \"2\", \"values\": [\"b\"], \"count\": 1}
+
     # ungroup
     $ cat input
     1	a,c,a
@@ -57,159 +93,2788 @@ By default, it assumes the input is sorted by the first field.
     1	a
     1	c
     2	b
+
+    # set `--columns` to explode several grouped fields into the cross product of their tokens,
+    # or with `--zip`, pair up their tokens in order
+    $ cat pairs
+    1	a,b	x,y
+    $ group -i --columns 2,3 pairs
+    1	a	x
+    1	a	y
+    1	b	x
+    1	b	y
+    $ group -i --columns 2,3 --zip pairs
+    1	a	x
+    1	b	y
+
+    # set `--enumerate` to print the position of each token within its group
+    $ group -i --enumerate input
+    1	1	a
+    1	2	c
+    1	3	a
+    2	1	b
+
+    # set `--csv` for CSV input, where quoted fields may contain the delimiter
+    $ cat input.csv
+    1,\"Seoul, KR\"
+    1,Tokyo
+    $ group --csv input.csv
+    1,\"\"\"Seoul, KR\"\",Tokyo\"
+
+    # set `--escape` or `--quote` for values that contain the token delimiter,
+    # and the same with `-i` to split them back
+    $ cat input.tsv
+    1	Seoul, KR
+    1	Tokyo
+    $ group --escape input.tsv
+    1	Seoul\\, KR,Tokyo
+    $ group --quote input.tsv
+    1	\"Seoul, KR\",Tokyo
+
+    # the output fields are delimited like the input, so that `group -i` can split them back;
+    # set `--output-delim` otherwise
+    $ cat input.txt
+    1 a
+    1 b
+    2 c
+    $ group -f ' ' --output-delim ';' input.txt
+    1;a,b
+    2;c
+
+    # set `-a` to aggregate numeric values instead of concatenating them
+    $ cat input
+    x	1
+    x	2.5
+    y	4
+
+    $ group -a sum input
+    x	3.5
+    y	4
+
+    # `-a stats` prints count, sum, mean, min, max and stddev in one pass
+    $ group -a stats input
+    x	2	3.5	1.75	1	2.5	1.0606601717798212
+    y	1	4	4	4	4	
+
+    # `-a first` or `-a last` keeps the value as is, e.g., the latest status of each id
+    $ group -a last input
+    x	2.5
+    y	4
+
+    # set `--rle` to print repeated adjacent values once with their count; `-i --rle` expands them
+    $ printf 'x\\tup\\nx\\tup\\nx\\tdown\\nx\\tup\\n' | group --rle
+    x	up*2,down,up
+
+    # set `-k` and `-v` to group other fields than the first and the second
+    $ cat input
+    GET	/a	12
+    GET	/b	7
+    POST	/a	30
+
+    $ group -m -k2 -v3 -a max input
+    /a	30
+    /b	7
+
+    # set `-k` with several fields to group by their combination
+    $ group -k1,2 -v3 -a sum input
+    GET	/a	12
+    GET	/b	7
+    POST	/a	30
+
+    # set `-v` with several fields to group each into its own column
+    $ group -v2,3 input
+    GET	/a,/b	12,7
+    POST	/a	30
+
+    # set `--max-size N` to truncate huge groups, marking how many values there are
+    $ group --max-size 1 input
+    GET	/a,...(2)
+    POST	/a
+
+    # set `-z` for NUL-terminated records, e.g., for values with newlines
+    $ printf 'a\\tx\\ny\\0a\\tz\\0' | group -z | tr '\\0' '\\n'
+    a	x
+    y,z
+
+    # `-f` and `-t` may be several characters, including escapes such as \\t and \\0
+    $ printf 'a||1\\na||2\\nb||3\\n' | group -f '||' -t '<SEP>'
+    a||1<SEP>2
+    b||3
+
+    # set `--merge` to merge groups of the same key, e.g., from several runs over shards
+    $ printf 'a\\t1,2\\nb\\t3\\na\\t2,4\\n' | group -m --sort-keys --merge -u
+    a	1,2,4
+    b	3
+
+    # set `--threads N` with `-m` to group huge unsorted input in N threads
+    $ group -m --threads 8 huge-input
+
+    # set `--max-memory SIZE` with `-m` to fail clearly instead of running out of memory,
+    # and `--spill` to spill the largest groups to temporary files instead
+    $ group -m --max-memory 4G --spill huge-input
+
+    # lines that lack the fields are skipped; set `--on-missing empty` to group them
+    # as if the missing fields were empty, or `--on-missing error` to fail instead
+    $ printf 'a\\t1\\na\\nb\\t2\\n' | group --on-missing empty
+    a	1,
+    b	2
+
+    # set `--pivot FIELD` for a matrix with a column for each value of the field,
+    # and `--fill STR` for the empty cells
+    $ cat monthly
+    east	jan	10
+    east	feb	20
+    west	jan	30
+
+    $ group -v3 --pivot 2 --fill 0 monthly
+    	feb	jan
+    east	20	10
+    west	0	30
+
+    # set `--join` to join the groups of two inputs sorted by key;
+    # `--join=left` or `--join=full` to also print the keys missing from the second or either
+    $ cat orders
+    a	1
+    a	2
+    b	3
+
+    $ cat shipments
+    a	x
+    c	y
+
+    $ group --join=full orders shipments
+    a	1,2	x
+    b	3	
+    c		y
+
+    # set `--summary` to print how many groups there are of each size and how many records
+    # they have, followed by the totals, e.g., to see how skewed the keys are
+    $ group -m --summary input
+    1	1	1
+    2	1	2
+    total	2	3
+
+    # set `--batch N` to group every N lines as a whole, e.g., to pack requests into batches
+    $ seq 5 | group --batch 2
+    1	1,2
+    2	3,4
+    3	5
+
+    # set `-H` if the first line is a header; `-k` and `-v` may then refer to fields by name
+    $ cat requests
+    method	path	ms
+    GET	/a	12
+    GET	/b	7
+    POST	/a	30
+
+    $ group -H -k method -v ms -a sum requests
+    method	sum(ms)
+    GET	19
+    POST	30
+
+    # set `--key-slice` or `--key-regex` to group by a part of the key, e.g., the hour
+    $ cat log
+    2024-05-01T10:02	a
+    2024-05-01T10:41	b
+    2024-05-01T11:15	c
+
+    $ group --key-slice 1:13 log
+    2024-05-01T10	a,b
+    2024-05-01T11	c
+
+    $ group --key-regex 'T([0-9]+):' log
+    10	a,b
+    11	c
+
+    # set `--trim` and `--fold-case` to ignore whitespace around keys and their case
+    $ printf 'Foo\\t1\\nfoo \\t2\\nfoo\\t3\\n' | group -m --trim --fold-case
+    foo	1,2,3
+
+    # set `--min-size N` to drop the groups of fewer than N records,
+    # or with `--small-groups FILE`, to write them there instead
+    $ group --min-size 2 --small-groups singletons input
+    GET	/a,/b
+
+    # set `--nest` to sub-group the values of each group by another field
+    $ cat sales
+    KR	Seoul	3
+    KR	Busan	1
+    KR	Seoul	5
+    US	NYC	2
+
+    $ group --nest 2 -v3 -t '|' sales
+    KR	Seoul:3|5	Busan:1
+    US	NYC:2
+
+    # set `--rest` to group the rest of the line after the key
+    $ group --rest input
+    GET	/a	12,/b	7
+    POST	/a	30
+
+    # set `-c` to prefix the values with the size of the group, or `--count-only` for just that
+    $ group -c input
+    GET	2	/a,/b
+    POST	1	/a
+
+    # set `--with-counts` along with `-u` to count the occurrences of each value
+    $ group -m -u --with-counts -k2 -v1 input
+    /a	GET:1,POST:1
+    /b	GET:1
+
+    # set `--stable-unique` instead to keep the values in the order they first occur
+    $ cat events
+    u1	login
+    u1	view
+    u1	login
+    u1	buy
+
+    $ group --stable-unique events
+    u1	login,view,buy
+
+    # set `-i --weighted` to repeat the values with counts, e.g., of `--with-counts`
+    $ printf '/a\\tGET:1,POST:2\\n' | group -i --weighted
+    /a	GET
+    /a	POST
+    /a	POST
+
+    # set `--top N` to keep the first N values, or the N largest with `--by-numeric`
+    $ group --top 1 --by-numeric -k1 -v3 input
+    GET	12
+    POST	30
+
+    # `-a median` or `-a pN` for percentiles; set `--approx N` to sample huge groups
+    $ group -a p95 --approx 100000 latency-by-endpoint
 "
 )]
 struct Arguments {
-    /// Field delimiter character
-    #[arg(short, default_value_t = '\t')]
-    field_delim: char,
-    /// Token delimiter character for output
-    #[arg(short, default_value_t = ',')]
-    token_delim: char,
+    /// Field delimiter of one or more characters, which may include the escapes \t, \n, \0
+    /// and \\ [default: \t, or `,` with `--csv`]
+    #[arg(short, value_parser = parse_delim)]
+    field_delim: Option<String>,
+    /// Field delimiter for output [default: the field delimiter]
+    #[arg(long, value_name = "DELIM", value_parser = parse_delim)]
+    output_delim: Option<String>,
+    /// Token delimiter for output, with the same escapes as `-f`
+    #[arg(short, default_value = ",", value_parser = parse_delim)]
+    token_delim: String,
+    /// records of the input and output are terminated by NUL instead of newline
+    #[arg(short, default_value_t = false)]
+    zero_terminated: bool,
     /// inverse operation, which un-groups the input
     #[arg(short, default_value_t = false)]
     inverse: bool,
+    /// with `-i`, print the 1-based position of each token within its group after the key
+    #[arg(long, default_value_t = false, requires = "inverse")]
+    enumerate: bool,
+    /// with `-i`, grouped fields to explode into the cross product of their tokens
+    #[arg(
+        long,
+        value_name = "FIELDS",
+        value_delimiter = ',',
+        requires = "inverse",
+        conflicts_with_all = ["value_field", "rest"]
+    )]
+    columns: Option<Vec<String>>,
+    /// with `--columns`, pair up the i-th tokens of the fields instead, as `-v` does
+    #[arg(long, default_value_t = false, requires = "columns")]
+    zip: bool,
     /// apply unique tokens after grouping / before un-grouping
     #[arg(short, default_value_t = false)]
     unique: bool,
+    /// like `-u`, but keep the values in the order they first occur instead of sorting them
+    #[arg(long, default_value_t = false, conflicts_with = "unique")]
+    stable_unique: bool,
+    /// print repeated adjacent values of a group once as value*count;
+    /// with `-i`, expand them back
+    #[arg(long, default_value_t = false, conflicts_with_all = ["unique", "stable_unique", "aggregate"])]
+    rle: bool,
+    /// merge input that is already grouped, splitting its values on the token delimiter,
+    /// e.g., the outputs of several runs over shards of the input
+    #[arg(long, default_value_t = false, conflicts_with = "inverse")]
+    merge: bool,
+    /// group every N consecutive lines as a whole, with the 1-based number of the batch as the key
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = [
+            "inverse", "hashmap", "external", "check_sorted", "join", "key_field", "value_field",
+            "nest", "pivot", "header", "rest", "merge"
+        ]
+    )]
+    batch: Option<usize>,
     /// for unsorted input, use hashmap (larger time & space complexity)
     #[arg(short = 'm', default_value_t = false)]
     hashmap: bool,
+    /// with `-m`, print the groups ordered by key instead of in arbitrary order
+    #[arg(long, default_value_t = false, requires = "hashmap")]
+    sort_keys: bool,
+    /// with `--sort-keys`, order the keys numerically; those that are not numbers come last
+    #[arg(long, default_value_t = false, requires = "sort_keys")]
+    numeric: bool,
+    /// with `-m`, print the groups in the order their keys first appear in the input
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "hashmap",
+        conflicts_with = "sort_keys"
+    )]
+    stable: bool,
+    /// with `-m`, group in N threads, each with the keys of its own shard
+    #[arg(long, value_name = "N", requires = "hashmap")]
+    threads: Option<usize>,
+    /// for huge unsorted input, sort it by key in runs spilled to temporary files before grouping
+    #[arg(long, default_value_t = false, conflicts_with_all = ["inverse", "hashmap"])]
+    external: bool,
+    /// with `--external` or `--spill`, directory for the temporary files [default: system temp directory]
+    #[arg(long, value_name = "DIR")]
+    tmpdir: Option<PathBuf>,
+    /// with `--external`, memory to sort in before spilling to disk, e.g., 512M or 4G [default: 256M];
+    /// with `-m`, memory for the groups, beyond which it fails unless `--spill` is set
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// with `-m --max-memory`, spill the largest groups to temporary files instead of failing
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "max_memory",
+        conflicts_with = "approx"
+    )]
+    spill: bool,
+    /// fail if a key reappears after other keys, i.e., the input is not sorted;
+    /// with `=fallback`, group a regular input file with the hashmap instead
+    #[arg(
+        long,
+        value_name = "ACTION",
+        value_parser = ["error", "fallback"],
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "error",
+        conflicts_with_all = ["inverse", "hashmap", "external"]
+    )]
+    check_sorted: Option<String>,
+    /// join the groups of INPUT and JOIN_INPUT, both sorted by key, printing the values of
+    /// each after the key of both; with `=left`, also the keys only in INPUT, and with `=full`,
+    /// also the keys only in JOIN_INPUT
+    #[arg(
+        long,
+        value_name = "TYPE",
+        value_parser = ["inner", "left", "full"],
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "inner",
+        requires = "join_input",
+        conflicts_with_all = [
+            "inverse", "hashmap", "external", "check_sorted", "header", "json", "nest", "pivot",
+            "min_size"
+        ]
+    )]
+    join: Option<String>,
+    /// what to do with lines that lack the key or value fields: skip them,
+    /// group them as if the missing fields were empty, or fail with the line number
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_parser = ["skip", "empty", "error"],
+        default_value = "skip"
+    )]
+    on_missing: String,
+    /// aggregate the values numerically instead of concatenating them:
+    /// sum, min, max, mean, count, product, stats, median, or pN for the Nth percentile;
+    /// or keep only the first or the last value with first or last
+    #[arg(short, value_parser = parse_aggregate, conflicts_with = "inverse")]
+    aggregate: Option<Aggregate>,
+    /// key field; if more than one, e.g., `-k 1,2`, the fields are combined into the key
+    #[arg(short, value_delimiter = ',', default_value = "1")]
+    key_field: Vec<String>,
+    /// value field; if more than one, e.g., `-v 2,3`, each is grouped into its own column
+    #[arg(short, value_delimiter = ',', default_value = "2")]
+    value_field: Vec<String>,
+    /// the first line is a header; it is printed with the output fields named after it,
+    /// and `-k` and `-v` may refer to fields by name
+    #[arg(short = 'H', default_value_t = false)]
+    header: bool,
+    /// prefix the grouped values with the number of records in the group
+    #[arg(short, default_value_t = false, conflicts_with = "inverse")]
+    count: bool,
+    /// print only the number of records in each group
+    #[arg(long, default_value_t = false, conflicts_with_all = ["inverse", "aggregate"])]
+    count_only: bool,
+    /// with `-u` or `--stable-unique`, follow each distinct value by the delimiter and its number of occurrences
+    #[arg(
+        long,
+        value_name = "DELIM",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ":",
+        conflicts_with_all = ["inverse", "aggregate"]
+    )]
+    with_counts: Option<String>,
+    /// with `-i`, repeat each value followed by the delimiter and a count, e.g., of
+    /// `--with-counts`, that many times
+    #[arg(
+        long,
+        value_name = "DELIM",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ":",
+        requires = "inverse",
+        conflicts_with = "rle"
+    )]
+    weighted: Option<String>,
+    /// keep only the first N values of each group
+    #[arg(long, value_name = "N", conflicts_with_all = ["inverse", "approx"])]
+    top: Option<usize>,
+    /// with `--top`, keep the N numerically largest values instead, in descending order
+    #[arg(long, default_value_t = false, requires = "top")]
+    by_numeric: bool,
+    /// keep only the first N values of each group, followed by the overflow marker
+    /// and the number of values in parentheses if there are more
+    #[arg(long, value_name = "N", conflicts_with_all = ["inverse", "top", "aggregate", "approx"])]
+    max_size: Option<usize>,
+    /// with `--max-size`, the token that marks a truncated group
+    #[arg(long, value_name = "STR", default_value = "...", requires = "max_size")]
+    overflow_marker: String,
+    /// group everything after the key field(s), delimiters included, as the value
+    #[arg(long, default_value_t = false, conflicts_with = "value_field")]
+    rest: bool,
+    /// parse the input as CSV, where quoted fields may contain the delimiter,
+    /// and quote the values as with `--quote` and then the output fields as necessary
+    #[arg(long, default_value_t = false, conflicts_with = "rest")]
+    csv: bool,
+    /// escape the token delimiter and backslashes within values with a backslash,
+    /// or with `-i`, split only on unescaped token delimiters and unescape the tokens
+    #[arg(long, default_value_t = false, conflicts_with = "quote")]
+    escape: bool,
+    /// enclose values that contain the token delimiter in double quotes,
+    /// or with `-i`, split only on token delimiters outside double quotes
+    #[arg(long, default_value_t = false)]
+    quote: bool,
+    /// sub-group the values of each group by this field, printing each sub-group
+    /// as a field of its key, a colon and its values
+    #[arg(long, value_name = "FIELD", conflicts_with_all = ["inverse", "rest"])]
+    nest: Option<String>,
+    /// print a matrix with a row for each key and a column for each distinct value of FIELD
+    /// in sorted order, after a header row; each cell holds the values of the key and column
+    #[arg(
+        long,
+        value_name = "FIELD",
+        conflicts_with_all = ["inverse", "rest", "nest", "json", "small_groups"]
+    )]
+    pivot: Option<String>,
+    /// with `--pivot`, the value of the cells without any records
+    #[arg(long, value_name = "STR", default_value = "", requires = "pivot")]
+    fill: String,
+    /// group by the part of the key that matches the POSIX extended regex,
+    /// or by its first parenthesized group if any; lines whose key does not match are skipped
+    #[arg(long, value_name = "PATTERN", conflicts_with = "inverse")]
+    key_regex: Option<String>,
+    /// group by the characters START to END of the key, 1-based and inclusive, e.g., `1:10`;
+    /// either may be omitted
+    #[arg(long, value_name = "START:END", value_parser = parse_slice, conflicts_with_all = ["inverse", "key_regex"])]
+    key_slice: Option<(usize, usize)>,
+    /// ignore case in keys, printing them in lower case
+    #[arg(long, default_value_t = false, conflicts_with = "inverse")]
+    fold_case: bool,
+    /// ignore leading and trailing whitespace in keys
+    #[arg(long, default_value_t = false, conflicts_with = "inverse")]
+    trim: bool,
+    /// drop the groups of fewer than N records
+    #[arg(long, value_name = "N", conflicts_with = "inverse")]
+    min_size: Option<usize>,
+    /// with `--min-size`, write the dropped groups to FILE instead
+    #[arg(long, value_name = "FILE", requires = "min_size")]
+    small_groups: Option<String>,
+    /// estimate percentiles from a uniform sample of at most N values per group
+    #[arg(long, value_name = "N")]
+    approx: Option<usize>,
+    /// print each group as a JSON object with the key, the values or their aggregate, and the count
+    #[arg(long, default_value_t = false, conflicts_with = "inverse")]
+    json: bool,
+    /// instead of the groups, print the number of groups of each size and of records in them,
+    /// followed by the totals
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["inverse", "json", "pivot", "join", "min_size"]
+    )]
+    summary: bool,
     /// Input file; If omitted, read from stdin
     input: Option<String>,
+    /// with `--join`, the second input file
+    #[arg(requires = "join")]
+    join_input: Option<String>,
 }
 
-fn group_hashmap<R: BufRead, W: Write>(
-    ifs: R,
-    mut ofs: W,
-    field_delim: &str,
-    token_delim: &str,
-    unique: bool,
-) -> io::Result<()> {
-    let mut map = HashMap::<String, Vec<String>>::new();
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+    Mean,
+    Count,
+    Product,
+    // count, sum, mean, min, max and sample standard deviation in separate columns
+    Stats,
+    Percentile(f64),
+    // the first or the last value as is, keeping only that one in memory
+    First,
+    Last,
+}
 
-    for line in ifs.lines() {
-        let line = line?;
-        let fields: Vec<&str> = line.split(field_delim).take(2).collect();
-        if fields.len() < 2 {
+fn parse_aggregate(s: &str) -> Result<Aggregate, String> {
+    match s {
+        "sum" => Ok(Aggregate::Sum),
+        "min" => Ok(Aggregate::Min),
+        "max" => Ok(Aggregate::Max),
+        "mean" => Ok(Aggregate::Mean),
+        "count" => Ok(Aggregate::Count),
+        "product" => Ok(Aggregate::Product),
+        "stats" => Ok(Aggregate::Stats),
+        "median" => Ok(Aggregate::Percentile(50.0)),
+        "first" => Ok(Aggregate::First),
+        "last" => Ok(Aggregate::Last),
+        _ => match s.strip_prefix('p').map(str::parse::<f64>) {
+            Some(Ok(x)) if (0.0..=100.0).contains(&x) => Ok(Aggregate::Percentile(x)),
+            _ => Err(
+                "expected one of sum, min, max, mean, count, product, stats, median, pN, first, last"
+                    .to_owned(),
+            ),
+        },
+    }
+}
+
+fn aggregate_name(aggregate: Aggregate) -> String {
+    match aggregate {
+        Aggregate::Sum => "sum".to_owned(),
+        Aggregate::Min => "min".to_owned(),
+        Aggregate::Max => "max".to_owned(),
+        Aggregate::Mean => "mean".to_owned(),
+        Aggregate::Count => "count".to_owned(),
+        Aggregate::Product => "product".to_owned(),
+        Aggregate::Stats => "stats".to_owned(),
+        Aggregate::Percentile(p) => format!("p{}", p),
+        Aggregate::First => "first".to_owned(),
+        Aggregate::Last => "last".to_owned(),
+    }
+}
+
+#[test]
+fn test_parse_aggregate() {
+    assert_eq!(parse_aggregate("median"), Ok(Aggregate::Percentile(50.0)));
+    assert_eq!(parse_aggregate("p95"), Ok(Aggregate::Percentile(95.0)));
+    assert_eq!(parse_aggregate("p99.9"), Ok(Aggregate::Percentile(99.9)));
+    assert!(parse_aggregate("p101").is_err());
+    assert!(parse_aggregate("pp").is_err());
+}
+
+// a delimiter with the escapes replaced; `\0` stands for NUL, which cannot be passed as an argument
+fn parse_delim(s: &str) -> Result<String, String> {
+    let mut delim = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            delim.push(c);
             continue;
         }
-        map.entry(fields[0].to_owned())
-            .or_default()
-            .push(fields[1].to_owned());
+        delim.push(match chars.next() {
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('0') => '\0',
+            Some('\\') | None => '\\', // a trailing backslash stands for itself
+            Some(x) => return Err(format!("unknown escape `\\{}`", x)),
+        });
+    }
+    match delim.is_empty() {
+        true => Err("the delimiter must not be empty".to_owned()),
+        false => Ok(delim),
     }
+}
 
-    for (key, mut tokens) in map.into_iter() {
-        if unique {
-            tokens.sort();
-            tokens.dedup();
-        }
-        writeln!(ofs, "{}\t{}", &key, tokens.join(token_delim))?;
+#[test]
+fn test_parse_delim() {
+    assert_eq!(parse_delim(","), Ok(",".to_owned()));
+    assert_eq!(parse_delim("\\0"), Ok("\0".to_owned()));
+    assert_eq!(parse_delim("<SEP>"), Ok("<SEP>".to_owned()));
+    assert_eq!(parse_delim("\\t|\\\\"), Ok("\t|\\".to_owned()));
+    assert_eq!(parse_delim("\\"), Ok("\\".to_owned()));
+    assert!(parse_delim("").is_err());
+    assert!(parse_delim("\\x").is_err());
+}
+
+// 0-indexed half-open range of the 1-based inclusive START:END
+fn parse_slice(s: &str) -> Result<(usize, usize), String> {
+    let err =
+        || "expected START:END, where 1 <= START <= END, and either may be omitted".to_owned();
+    let (start, end) = s.split_once(':').ok_or_else(err)?;
+    let start = match start {
+        "" => 1,
+        x => x.parse::<usize>().map_err(|_| err())?,
+    };
+    let end = match end {
+        "" => usize::MAX,
+        x => x.parse::<usize>().map_err(|_| err())?,
+    };
+    match 1 <= start && start <= end {
+        true => Ok((start - 1, end)),
+        false => Err(err()),
     }
+}
 
-    Ok(())
+#[test]
+fn test_parse_slice() {
+    assert_eq!(parse_slice("1:10"), Ok((0, 10)));
+    assert_eq!(parse_slice("3:"), Ok((2, usize::MAX)));
+    assert_eq!(parse_slice(":4"), Ok((0, 4)));
+    assert!(parse_slice("0:4").is_err());
+    assert!(parse_slice("5:4").is_err());
+    assert!(parse_slice("5").is_err());
 }
 
-fn group<R: BufRead, W: Write>(
-    ifs: R,
-    mut ofs: W,
-    field_delim: &str,
-    token_delim: &str,
+// memory to sort in with `--external` unless `--max-memory` is set
+const MAX_MEMORY: usize = 256 << 20;
+
+// how values that contain the token delimiter are kept apart
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    Backslash,
+    Quote,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Join {
+    Inner,
+    Left,
+    Full,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OnMissing {
+    Skip,
+    Empty,
+    Error,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OnMemoryLimit {
+    Abort,
+    Spill,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OnUnsorted {
+    Error,
+    Fallback,
+}
+
+struct ProgramOption {
+    field_delim: String,
+    output_delim: String,
+    record_delim: char,
+    csv: bool,
+    escape: Option<Escape>,
+    token_delim: String,
+    inverse: bool,
+    enumerate: bool,
+    cross: bool,
     unique: bool,
-) -> io::Result<()> {
-    let mut prev_key = Option::<String>::None;
-    let mut tokens = Vec::<String>::new();
+    stable_unique: bool,
+    rle: bool,
+    merge: bool,
+    batch: Option<usize>,
+    hashmap: bool,
+    sort_keys: bool,
+    numeric: bool,
+    stable: bool,
+    threads: usize,
+    external: bool,
+    tmpdir: PathBuf,
+    max_memory: usize,
+    on_memory_limit: Option<OnMemoryLimit>,
+    check_sorted: Option<OnUnsorted>,
+    on_missing: OnMissing,
+    key_idx: Vec<usize>,     // 0-index
+    value_idx: Vec<usize>,   // 0-index
+    nest_idx: Option<usize>, // 0-index
+    rest: bool,
+    count: bool,
+    count_only: bool,
+    top: Option<usize>,
+    by_numeric: bool,
+    max_size: Option<usize>,
+    overflow_marker: String,
+    with_counts: Option<String>,
+    weighted: Option<String>,
+    aggregate: Option<Aggregate>,
+    sample: Option<usize>,
+    json: bool,
+    summary: Option<Mutex<BTreeMap<usize, usize>>>, // the number of groups of each size
+    header: bool,
+    key_fields: Vec<String>,
+    value_fields: Vec<String>,
+    nest_field: Option<String>,
+    pivot: Option<Mutex<Pivot>>,
+    fill: String,
+    key_regex: Option<Regex>,
+    key_slice: Option<(usize, usize)>,
+    fold_case: bool,
+    trim: bool,
+    min_size: usize,
+    small_groups: Option<Mutex<BufWriter<File>>>,
+    join: Option<Join>,
+    join_file: String,
+    input_file: String,
+}
 
-    for line in ifs.lines() {
-        let line = line?;
-        let fields: Vec<&str> = line.split(field_delim).take(2).collect();
-        if fields.len() < 2 {
-            continue;
+fn parse_arguments() -> Result<ProgramOption, String> {
+    let args = Arguments::parse();
+    let input_file = match args.input.is_some() && args.input != Some("-".to_owned()) {
+        true => args.input.unwrap(),
+        false => "/dev/stdin".to_owned(),
+    };
+
+    let sample = match (args.approx, args.aggregate) {
+        (None, _) => None,
+        (Some(0), _) => return Err("--approx must be positive".to_owned()),
+        (Some(n), Some(Aggregate::Percentile(_))) => Some(n),
+        _ => return Err("--approx requires `-a median` or `-a pN`".to_owned()),
+    };
+    let unique = args.unique || args.stable_unique;
+    let field_delim = match (args.field_delim, args.csv) {
+        (Some(x), _) => x,
+        (None, true) => ",".to_owned(),
+        (None, false) => "\t".to_owned(),
+    };
+    if args.max_memory.is_some() && !args.external && !args.hashmap {
+        return Err("--max-memory requires `--external` or `-m`".to_owned());
+    }
+    if args.tmpdir.is_some() && !args.external && !args.spill {
+        return Err("--tmpdir requires `--external` or `--spill`".to_owned());
+    }
+    if args.with_counts.is_some() && !unique {
+        return Err("--with-counts requires `-u` or `--stable-unique`".to_owned());
+    }
+
+    let mut program_option = ProgramOption {
+        output_delim: args.output_delim.unwrap_or(field_delim.clone()),
+        field_delim,
+        record_delim: match args.zero_terminated {
+            true => '\0',
+            false => '\n',
+        },
+        csv: args.csv,
+        escape: match (args.escape, args.quote || args.csv) {
+            (true, _) => Some(Escape::Backslash),
+            (false, true) => Some(Escape::Quote),
+            (false, false) => None,
+        },
+        token_delim: args.token_delim,
+        inverse: args.inverse,
+        enumerate: args.enumerate,
+        cross: args.columns.is_some() && !args.zip,
+        unique,
+        stable_unique: args.stable_unique,
+        rle: args.rle,
+        merge: args.merge,
+        batch: match args.batch {
+            Some(0) => return Err("--batch must be positive".to_owned()),
+            x => x,
+        },
+        hashmap: args.hashmap,
+        sort_keys: args.sort_keys,
+        numeric: args.numeric,
+        stable: args.stable,
+        threads: match args.threads {
+            Some(0) => return Err("--threads must be positive".to_owned()),
+            x => x.unwrap_or(1),
+        },
+        external: args.external,
+        tmpdir: args.tmpdir.unwrap_or_else(std::env::temp_dir),
+        max_memory: args.max_memory.unwrap_or(MAX_MEMORY),
+        on_memory_limit: match (args.hashmap && args.max_memory.is_some(), args.spill) {
+            (false, _) => None,
+            (true, false) => Some(OnMemoryLimit::Abort),
+            (true, true) => Some(OnMemoryLimit::Spill),
+        },
+        check_sorted: match args.check_sorted.as_deref() {
+            None => None,
+            Some("fallback") => Some(OnUnsorted::Fallback),
+            Some(_) => Some(OnUnsorted::Error),
+        },
+        on_missing: match args.on_missing.as_str() {
+            "empty" => OnMissing::Empty,
+            "error" => OnMissing::Error,
+            _ => OnMissing::Skip,
+        },
+        header: args.header,
+        key_fields: args.key_field,
+        value_fields: args.columns.unwrap_or(args.value_field),
+        key_idx: vec![], // resolved below or after reading the header
+        nest_idx: None,  // resolved below or after reading the header
+        nest_field: args.nest.or(args.pivot.clone()),
+        pivot: args.pivot.map(|_| Mutex::new(Pivot::default())),
+        fill: args.fill,
+        key_regex: args.key_regex.as_deref().map(Regex::new).transpose()?,
+        key_slice: args.key_slice,
+        fold_case: args.fold_case,
+        trim: args.trim,
+        min_size: args.min_size.unwrap_or(0),
+        small_groups: match args.small_groups {
+            Some(path) => {
+                let file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+                Some(Mutex::new(BufWriter::new(file)))
+            }
+            None => None,
+        },
+        value_idx: vec![], // resolved below or after reading the header
+        rest: args.rest,
+        count: args.count,
+        count_only: args.count_only || args.summary, // the values are not needed for the summary
+        top: args.top,
+        max_size: args.max_size,
+        overflow_marker: args.overflow_marker,
+        by_numeric: args.by_numeric,
+        with_counts: args.with_counts,
+        weighted: args.weighted,
+        aggregate: args.aggregate,
+        sample,
+        json: args.json,
+        summary: args.summary.then(|| Mutex::new(BTreeMap::new())),
+        join: match args.join.as_deref() {
+            None => None,
+            Some("left") => Some(Join::Left),
+            Some("full") => Some(Join::Full),
+            Some(_) => Some(Join::Inner),
+        },
+        join_file: args.join_input.unwrap_or_default(),
+        input_file,
+    };
+    if !program_option.header {
+        resolve_fields(&mut program_option, &[])?;
+    }
+    Ok(program_option)
+}
+
+// 0-index of the field given by its 1-based number, or by its name in the header
+fn field_index(field: &str, names: &[Cow<str>]) -> Result<usize, String> {
+    match field.parse::<usize>() {
+        Ok(0) => Err("key and value fields must be 1 or greater".to_owned()),
+        Ok(x) => Ok(x - 1),
+        Err(_) if names.is_empty() => Err(format!(
+            "field `{}` must be a number, or a name in the header with `-H`",
+            field
+        )),
+        Err(_) => names
+            .iter()
+            .position(|name| name == field)
+            .ok_or(format!("no field named `{}` in the header", field)),
+    }
+}
+
+#[test]
+fn test_field_index() {
+    let names = [Cow::Borrowed("id"), Cow::Borrowed("name")];
+    assert_eq!(field_index("2", &names), Ok(1));
+    assert_eq!(field_index("name", &names), Ok(1));
+    assert!(field_index("0", &names).is_err());
+    assert!(field_index("age", &names).is_err());
+    assert!(field_index("name", &[]).is_err());
+}
+
+fn resolve_fields(program_option: &mut ProgramOption, names: &[Cow<str>]) -> Result<(), String> {
+    program_option.key_idx = program_option
+        .key_fields
+        .iter()
+        .map(|field| field_index(field, names))
+        .collect::<Result<_, _>>()?;
+    program_option.value_idx = match program_option.rest {
+        // right after the last key field
+        true => vec![program_option.key_idx.iter().max().map_or(0, |x| x + 1)],
+        false => program_option
+            .value_fields
+            .iter()
+            .map(|field| field_index(field, names))
+            .collect::<Result<_, _>>()?,
+    };
+    program_option.nest_idx = match &program_option.nest_field {
+        Some(field) => Some(field_index(field, names)?),
+        None => None,
+    };
+    Ok(())
+}
+
+// the header of the output, with the fields named after those of the input;
+// aggregated fields are named like `sum(price)`
+fn output_header(names: &[Cow<str>], program_option: &ProgramOption) -> String {
+    let name = |idx: usize| names.get(idx).map_or("", Cow::as_ref);
+    let rest_name = |idx: usize| {
+        names
+            .get(idx..)
+            .unwrap_or_default()
+            .join(&program_option.field_delim)
+    };
+    let key: Vec<Cow<str>> = program_option
+        .key_idx
+        .iter()
+        .map(|&idx| quote(name(idx), program_option))
+        .collect();
+    let mut fields = vec![key.join(&program_option.field_delim)];
+    if !program_option.inverse && (program_option.count || program_option.count_only) {
+        fields.push("count".to_owned());
+    }
+    if program_option.enumerate {
+        fields.push("position".to_owned());
+    }
+    if !program_option.count_only {
+        for &idx in &program_option.value_idx {
+            match program_option.aggregate {
+                None if program_option.rest => fields.push(rest_name(idx)),
+                None => fields.push(quote(name(idx), program_option).into_owned()),
+                Some(Aggregate::Stats) => {
+                    for stat in ["count", "sum", "mean", "min", "max", "stddev"] {
+                        let field = format!("{}({})", stat, name(idx));
+                        fields.push(quote(&field, program_option).into_owned());
+                    }
+                }
+                Some(x) => {
+                    let field = format!("{}({})", aggregate_name(x), name(idx));
+                    fields.push(quote(&field, program_option).into_owned());
+                }
+            }
         }
-        if Some(fields[0]) != prev_key.as_deref() {
-            if prev_key.is_some() {
-                if unique {
-                    tokens.sort();
-                    tokens.dedup();
+    }
+    if let Some(idx) = program_option.nest_idx {
+        let nested = format!("{}:{}", name(idx), fields.split_off(1).join(":"));
+        fields.push(nested);
+    }
+    fields.join(&program_option.output_delim)
+}
+
+// numeric aggregation of the tokens; those that are not numbers are skipped
+fn aggregate(key: &str, tokens: &[String], aggregate: Aggregate) -> String {
+    match aggregate {
+        Aggregate::Count => return tokens.len().to_string(),
+        Aggregate::First => return tokens.first().cloned().unwrap_or_default(),
+        Aggregate::Last => return tokens.last().cloned().unwrap_or_default(),
+        _ => {}
+    }
+    let values: Vec<f64> = tokens
+        .iter()
+        .filter_map(|token| match token.trim().parse::<f64>() {
+            Ok(x) => Some(x),
+            Err(_) => {
+                eprintln!("{}: cannot parse `{}` into f64; skipping", key, token);
+                None
+            }
+        })
+        .collect();
+    if values.is_empty() && matches!(aggregate, Aggregate::Min | Aggregate::Max | Aggregate::Mean) {
+        return String::new(); // undefined
+    }
+    if let Aggregate::Stats = aggregate {
+        return stats(&values);
+    }
+    if let Aggregate::Percentile(p) = aggregate {
+        return percentile(values, p).map_or(String::new(), |x| x.to_string());
+    }
+    let result: f64 = match aggregate {
+        Aggregate::Sum => values.iter().fold(0.0, |acc, x| acc + x),
+        Aggregate::Product => values.iter().product(),
+        Aggregate::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+        Aggregate::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        Aggregate::Mean => values.iter().sum::<f64>() / values.len() as f64,
+        _ => unreachable!(),
+    };
+    result.to_string()
+}
+
+// linear interpolation between the closest ranks, as in numpy
+fn percentile(mut values: Vec<f64>, p: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let rank = p / 100.0 * (values.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    Some(values[lo] + (values[hi] - values[lo]) * (rank - lo as f64))
+}
+
+#[test]
+fn test_percentile() {
+    let values = vec![15.0, 20.0, 35.0, 40.0, 50.0];
+    assert_eq!(percentile(values.clone(), 50.0), Some(35.0));
+    assert_eq!(percentile(values.clone(), 0.0), Some(15.0));
+    assert_eq!(percentile(values.clone(), 100.0), Some(50.0));
+    assert_eq!(percentile(values, 40.0), Some(29.0));
+    assert_eq!(percentile(vec![], 50.0), None);
+}
+
+// count, sum, mean, min, max and stddev columns; undefined ones are left empty
+fn stats(values: &[f64]) -> String {
+    let mut sum = 0.0;
+    let mut min = f64::INFINITY;
+    let mut max = f64::NEG_INFINITY;
+    // Welford's algorithm, which is numerically stable
+    let mut mean = 0.0;
+    let mut m2 = 0.0;
+    for (idx, &x) in values.iter().enumerate() {
+        let delta = x - mean;
+        mean += delta / (idx + 1) as f64;
+        m2 += delta * (x - mean);
+        sum += x;
+        min = min.min(x);
+        max = max.max(x);
+    }
+    let n = values.len();
+    let stddev = match n {
+        0 | 1 => String::new(),
+        _ => (m2 / (n - 1) as f64).sqrt().to_string(),
+    };
+    match n {
+        0 => format!("0\t{}\t\t\t\t", sum),
+        _ => format!("{}\t{}\t{}\t{}\t{}\t{}", n, sum, mean, min, max, stddev),
+    }
+}
+
+#[test]
+fn test_stats() {
+    assert_eq!(
+        stats(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0])
+            .split('\t')
+            .count(),
+        6
+    );
+    assert_eq!(stats(&[1.0, 3.0]), "2\t4\t2\t1\t3\t1.4142135623730951");
+    assert_eq!(stats(&[5.0]), "1\t5\t5\t5\t5\t");
+    assert_eq!(stats(&[]), "0\t0\t\t\t\t");
+}
+
+#[test]
+fn test_aggregate() {
+    let tokens: Vec<String> = ["1", "2.5", "x", "-4"].map(String::from).to_vec();
+    assert_eq!(aggregate("k", &tokens, Aggregate::Sum), "-0.5");
+    assert_eq!(aggregate("k", &tokens, Aggregate::Min), "-4");
+    assert_eq!(aggregate("k", &tokens, Aggregate::Max), "2.5");
+    assert_eq!(aggregate("k", &tokens, Aggregate::Count), "4");
+    assert_eq!(aggregate("k", &tokens, Aggregate::Product), "-10");
+    assert_eq!(aggregate("k", &tokens[..2], Aggregate::Mean), "1.75");
+    assert_eq!(aggregate("k", &[], Aggregate::Sum), "0");
+    assert_eq!(aggregate("k", &[], Aggregate::Max), "");
+    assert_eq!(aggregate("k", &tokens, Aggregate::First), "1");
+    assert_eq!(aggregate("k", &tokens, Aggregate::Last), "-4");
+    assert_eq!(aggregate("k", &[], Aggregate::Last), "");
+}
+
+// the fields of a CSV line; a field in double quotes may contain the delimiter,
+// and `""` within it stands for a double quote
+fn split_csv<'a>(line: &'a str, delim: &str) -> Vec<Cow<'a, str>> {
+    let mut fields = Vec::new();
+    let mut rest = line;
+    loop {
+        let mut field = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let mut field = String::new();
+                let mut chars = quoted.char_indices();
+                rest = "";
+                while let Some((idx, c)) = chars.next() {
+                    match c {
+                        '"' if quoted[idx + 1..].starts_with('"') => {
+                            field.push('"');
+                            chars.next();
+                        }
+                        '"' => {
+                            rest = &quoted[idx + 1..];
+                            break;
+                        }
+                        c => field.push(c),
+                    }
                 }
-                writeln!(ofs, "{}\t{}", prev_key.unwrap(), tokens.join(token_delim))?;
+                Cow::Owned(field)
             }
-            prev_key = Some(fields[0].to_owned());
-            tokens.clear();
+            None => Cow::Borrowed(""),
+        };
+        // anything after the closing quote up to the delimiter is kept as is
+        let (end, next) = match rest.find(delim) {
+            Some(idx) => (idx, Some(idx + delim.len())),
+            None => (rest.len(), None),
+        };
+        match field {
+            Cow::Borrowed(_) => field = Cow::Borrowed(&rest[..end]),
+            Cow::Owned(ref mut x) => x.push_str(&rest[..end]),
+        }
+        fields.push(field);
+        match next {
+            Some(idx) => rest = &rest[idx..],
+            None => return fields,
         }
-        tokens.push(fields[1].to_owned());
     }
+}
 
-    writeln!(ofs, "{}\t{}", prev_key.unwrap(), tokens.join(token_delim))
+#[test]
+fn test_csv() {
+    let line = "a,\"b,c\",\"say \"\"hi\"\"\",,\"d\"e";
+    assert_eq!(split_csv(line, ","), ["a", "b,c", "say \"hi\"", "", "de"]);
+    assert_eq!(split_csv("", ","), [""]);
+    assert_eq!(split_csv("\"a", ","), ["a"]);
+
+    // `group --csv` quotes the values and then the field, and `group -i --csv` splits them back
+    let values = ["Seoul, KR", "say \"hi\"", "", "Tokyo"];
+    let field = values.map(|x| quote_csv(x, ",")).join(",");
+    let line = format!("1,{}", quote_csv(&field, ","));
+    assert_eq!(line, r#"1,"""Seoul, KR"",""say """"hi"""""",,Tokyo""#);
+    let fields = split_csv(&line, ",");
+    assert_eq!(fields, ["1", field.as_str()]);
+    assert_eq!(split_csv(&fields[1], ","), values);
 }
 
-fn ungroup<R: BufRead, W: Write>(
-    ifs: R,
-    mut ofs: W,
-    field_delim: &str,
-    token_delim: &str,
-    unique: bool,
-) -> io::Result<()> {
-    for line in ifs.lines() {
-        let line = line?;
-        let fields: Vec<&str> = line.split(field_delim).take(2).collect();
-        if fields.len() < 2 {
-            continue;
+// 0-index of the last of the key and value fields
+fn last_field(program_option: &ProgramOption) -> usize {
+    program_option
+        .key_idx
+        .iter()
+        .chain(&program_option.value_idx)
+        .chain(&program_option.nest_idx)
+        .fold(0, |acc, &x| acc.max(x))
+}
+
+// the key and value fields of the line, if they all exist;
+// the fields of a composite key are joined by the field delimiter,
+// and with `--csv`, the key is quoted as necessary to be printed as is;
+// with `--nest`, the nested key follows the values
+fn key_value<'a>(
+    line: &'a str,
+    program_option: &ProgramOption,
+) -> Option<(Cow<'a, str>, Vec<Cow<'a, str>>)> {
+    let last = last_field(program_option);
+    let mut fields: Vec<Cow<str>> = match (program_option.csv, program_option.rest) {
+        (true, _) => split_csv(line, &program_option.field_delim),
+        // the last field spans the remainder of the line
+        (false, true) => line
+            .splitn(last + 1, &program_option.field_delim)
+            .map(Cow::Borrowed)
+            .collect(),
+        (false, false) => line
+            .split(&program_option.field_delim)
+            .take(last + 1)
+            .map(Cow::Borrowed)
+            .collect(),
+    };
+    if program_option.on_missing == OnMissing::Empty && fields.len() <= last {
+        fields.resize(last + 1, Cow::Borrowed(""));
+    }
+    let values = program_option
+        .value_idx
+        .iter()
+        .chain(&program_option.nest_idx)
+        .map(|&idx| fields.get(idx).cloned())
+        .collect::<Option<Vec<_>>>()?;
+    if program_option.csv {
+        for idx in &program_option.key_idx {
+            let field = fields.get_mut(*idx)?;
+            if let Cow::Owned(x) = quote_csv(field, &program_option.field_delim) {
+                *field = Cow::Owned(x);
+            }
         }
-        let tokens = fields[1].split(token_delim);
-        match unique {
-            true => {
-                let mut tokens: Vec<&str> = tokens.collect();
-                tokens.sort();
-                tokens.dedup();
-                for token in tokens {
-                    writeln!(ofs, "{}\t{}", fields[0], token)?;
+    }
+    let key = match program_option.key_idx.as_slice() {
+        [idx] => fields.get(*idx)?.clone(),
+        key_idx => Cow::Owned(
+            key_idx
+                .iter()
+                .map(|&idx| fields.get(idx).map(Cow::as_ref))
+                .collect::<Option<Vec<_>>>()?
+                .join(&program_option.field_delim),
+        ),
+    };
+    Some((derive_key(key, program_option)?, values))
+}
+
+// the key normalized with `--trim` and `--fold-case`, and then
+// the part of it to group by with `--key-regex` or `--key-slice`, if any
+fn derive_key<'a>(mut key: Cow<'a, str>, program_option: &ProgramOption) -> Option<Cow<'a, str>> {
+    if program_option.trim {
+        key = match key {
+            Cow::Borrowed(x) => Cow::Borrowed(x.trim()),
+            Cow::Owned(x) => Cow::Owned(x.trim().to_owned()),
+        };
+    }
+    if program_option.fold_case && key.chars().any(char::is_uppercase) {
+        key = Cow::Owned(key.to_lowercase());
+    }
+    match (&program_option.key_regex, program_option.key_slice) {
+        (Some(regex), _) => regex.find(&key).map(|x| Cow::Owned(x.to_owned())),
+        (None, Some((start, end))) => Some(Cow::Owned(
+            key.chars().skip(start).take(end - start).collect(),
+        )),
+        (None, None) => Some(key),
+    }
+}
+
+// tokens collected for a key; with `--approx N`, a uniform sample of at most N of them
+#[derive(Default)]
+struct Tokens {
+    tokens: Vec<String>,
+    seen: usize,
+    rng: Rng, // seeded the same for every group so that the output is reproducible
+}
+
+impl Tokens {
+    fn push(&mut self, token: &str, sample: Option<usize>) {
+        self.seen += 1;
+        match sample {
+            // reservoir sampling
+            Some(n) if self.tokens.len() == n => {
+                let idx = self.rng.below(self.seen);
+                if idx < n {
+                    self.tokens[idx] = token.to_owned();
                 }
             }
-            false => {
-                for token in tokens {
-                    writeln!(ofs, "{}\t{}", fields[0], token)?;
+            _ => self.tokens.push(token.to_owned()),
+        }
+    }
+
+    // append the tokens of a later part of the same group
+    fn merge(&mut self, other: Tokens, program_option: &ProgramOption) {
+        self.seen += other.seen;
+        if program_option.aggregate == Some(Aggregate::Last) && !other.tokens.is_empty() {
+            self.tokens.clear();
+        }
+        self.tokens.extend(other.tokens);
+        match (program_option.top, program_option.by_numeric) {
+            (Some(n), true) => top_numeric(&mut self.tokens, n),
+            _ => {
+                if let Some(n) = value_limit(program_option) {
+                    self.tokens.truncate(n);
                 }
             }
         }
     }
-    Ok(())
+
+    // estimated bytes of the tokens
+    fn memory(&self) -> usize {
+        self.tokens
+            .iter()
+            .map(|token| token.len() + std::mem::size_of::<String>())
+            .sum()
+    }
 }
 
-fn main() -> io::Result<()> {
-    let args = Arguments::parse();
-    let input_file = match args.input.is_some() && args.input != Some("-".to_owned()) {
-        true => args.input.unwrap(),
-        false => "/dev/stdin".to_owned(),
-    };
-    let output_file = "/dev/stdout".to_owned();
+#[test]
+fn test_tokens_sample() {
+    let mut tokens = Tokens::default();
+    for x in 0..10000 {
+        tokens.push(&x.to_string(), Some(100));
+    }
+    assert_eq!(tokens.seen, 10000);
+    assert_eq!(tokens.tokens.len(), 100);
+    let median = aggregate("k", &tokens.tokens, Aggregate::Percentile(50.0));
+    assert!((2500.0..7500.0).contains(&median.parse::<f64>().unwrap()));
+}
 
-    let ifs = BufReader::new(File::open(input_file)?);
-    let ofs = BufWriter::new(File::create(output_file)?);
+// the grouped output of the tokens collected for the key
+fn reduce_column(key: &str, tokens: Tokens, program_option: &ProgramOption) -> String {
+    let seen = tokens.seen;
+    let tokens = select_tokens(tokens, program_option);
+    if let Some(x) = program_option.aggregate {
+        return aggregate(key, &tokens, x);
+    }
+    let mut tokens: Vec<Cow<str>> = tokens
+        .iter()
+        .map(|token| escape_token(token, program_option))
+        .collect();
+    if program_option.max_size.is_some_and(|n| seen > n) {
+        let marker = format!("{}({})", program_option.overflow_marker, seen);
+        tokens.push(Cow::Owned(marker));
+    }
+    tokens.join(&program_option.token_delim)
+}
 
-    match args.inverse {
-        false => match args.hashmap {
-            false => group(
-                ifs,
-                ofs,
-                &args.field_delim.to_string(),
-                &args.token_delim.to_string(),
-                args.unique,
-            ),
-            true => group_hashmap(
-                ifs,
-                ofs,
-                &args.field_delim.to_string(),
-                &args.token_delim.to_string(),
-                args.unique,
-            ),
-        },
-        true => ungroup(
-            ifs,
-            ofs,
-            &args.field_delim.to_string(),
-            &args.token_delim.to_string(),
-            args.unique,
+// the token escaped with `--escape` or `--quote` as necessary
+fn escape_token<'a>(token: &'a str, program_option: &ProgramOption) -> Cow<'a, str> {
+    let delim = program_option.token_delim.as_str();
+    match program_option.escape {
+        Some(Escape::Backslash) if token.contains(delim) || token.contains('\\') => Cow::Owned(
+            token
+                .replace('\\', "\\\\")
+                .replace(delim, &format!("\\{}", delim)),
         ),
+        Some(Escape::Quote) => quote_csv(token, delim),
+        _ => Cow::Borrowed(token),
+    }
+}
+
+// the tokens of a grouped value, split as escaped with `--escape` or `--quote`
+fn split_tokens<'a>(value: &'a str, program_option: &ProgramOption) -> Vec<Cow<'a, str>> {
+    let delim = program_option.token_delim.as_str();
+    match program_option.escape {
+        Some(Escape::Backslash) => split_escaped(value, delim)
+            .into_iter()
+            .map(Cow::Owned)
+            .collect(),
+        Some(Escape::Quote) => split_csv(value, delim),
+        None => value.split(delim).map(Cow::Borrowed).collect(),
+    }
+}
+
+// split on the delimiters not preceded by a backslash, and unescape the tokens
+fn split_escaped(value: &str, delim: &str) -> Vec<String> {
+    let mut tokens = vec![String::new()];
+    let mut chars = value.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        let token = tokens.last_mut().unwrap();
+        match c {
+            '\\' => token.extend(chars.next().map(|(_, c)| c)),
+            _ if value[idx..].starts_with(delim) => {
+                // skip the rest of the delimiter
+                for _ in 1..delim.chars().count() {
+                    chars.next();
+                }
+                tokens.push(String::new());
+            }
+            c => token.push(c),
+        }
+    }
+    tokens
+}
+
+#[test]
+fn test_split_escaped() {
+    assert_eq!(split_escaped("a\\,b,c\\\\,d", ","), ["a,b", "c\\", "d"]);
+    assert_eq!(split_escaped("", ","), [""]);
+    assert_eq!(split_escaped("a||b", "||"), ["a", "b"]);
+}
+
+// the tokens to print or aggregate, after `--top` and `-u`
+fn select_tokens(tokens: Tokens, program_option: &ProgramOption) -> Vec<String> {
+    let mut tokens = tokens.tokens;
+    if let (Some(n), true) = (program_option.top, program_option.by_numeric) {
+        top_numeric(&mut tokens, n);
+    }
+    if program_option.unique && program_option.stable_unique {
+        let counted = count_stable(tokens).into_iter();
+        tokens = match &program_option.with_counts {
+            Some(delim) => counted
+                .map(|(token, count)| format!("{}{}{}", token, delim, count))
+                .collect(),
+            None => counted.map(|(token, _)| token).collect(),
+        };
+    } else if program_option.unique {
+        tokens.sort();
+        match &program_option.with_counts {
+            Some(delim) => tokens = count_duplicates(tokens, delim),
+            None => tokens.dedup(),
+        }
+    }
+    if program_option.rle {
+        tokens = run_length(tokens);
+    }
+    tokens
+}
+
+// distinct tokens of the sorted tokens, each followed by the delimiter and its occurrences
+fn count_duplicates(tokens: Vec<String>, delim: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut count = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        count += 1;
+        if tokens.get(idx + 1) != Some(token) {
+            result.push(format!("{}{}{}", token, delim, count));
+            count = 0;
+        }
+    }
+    result
+}
+
+#[test]
+fn test_count_duplicates() {
+    let tokens: Vec<String> = ["a", "a", "b", "c", "c", "c"].map(String::from).to_vec();
+    assert_eq!(count_duplicates(tokens, ":"), ["a:2", "b:1", "c:3"]);
+    assert!(count_duplicates(vec![], ":").is_empty());
+}
+
+// distinct items along with their occurrences, in the order they first occur
+fn count_stable<T: Hash + Eq + Clone>(items: Vec<T>) -> Vec<(T, usize)> {
+    let mut index = HashMap::<T, usize>::new();
+    let mut result = Vec::<(T, usize)>::new();
+    for item in items {
+        match index.get(&item) {
+            Some(&idx) => result[idx].1 += 1,
+            None => {
+                index.insert(item.clone(), result.len());
+                result.push((item, 1));
+            }
+        }
+    }
+    result
+}
+
+#[test]
+fn test_count_stable() {
+    assert_eq!(
+        count_stable(vec!["c", "a", "c", "b", "a", "c"]),
+        [("c", 3), ("a", 2), ("b", 1)]
+    );
+}
+
+// runs of the same token as token*count
+fn run_length(tokens: Vec<String>) -> Vec<String> {
+    let mut result = Vec::<String>::new();
+    for (token, count) in tokens
+        .chunk_by(|a, b| a == b)
+        .map(|run| (&run[0], run.len()))
+    {
+        result.push(match count {
+            1 => token.clone(),
+            _ => format!("{}*{}", token, count),
+        });
+    }
+    result
+}
+
+// the tokens with each token, delimiter and count repeated count times,
+// e.g., token*count of `--rle`; the others are kept as is
+fn expand_counts<T: AsRef<str> + From<String> + Clone>(tokens: Vec<T>, delim: &str) -> Vec<T> {
+    let mut result = Vec::new();
+    for token in tokens {
+        let run = token
+            .as_ref()
+            .rsplit_once(delim)
+            .and_then(|(x, count)| Some((x.to_owned(), count.parse::<usize>().ok()?)));
+        match run {
+            Some((x, count)) => result.extend(std::iter::repeat_n(T::from(x), count)),
+            None => result.push(token),
+        }
+    }
+    result
+}
+
+#[test]
+fn test_run_length() {
+    let tokens: Vec<String> = ["a", "a", "a", "b", "a", "a"].map(String::from).to_vec();
+    let encoded = run_length(tokens.clone());
+    assert_eq!(encoded, ["a*3", "b", "a*2"]);
+    assert_eq!(expand_counts(encoded, "*"), tokens);
+    assert_eq!(expand_counts(vec!["x*y".to_owned()], "*"), ["x*y"]);
+    let weighted: Vec<String> = ["a:2", "b:0", "c"].map(String::from).to_vec();
+    assert_eq!(expand_counts(weighted, ":"), ["a", "a", "c"]);
+}
+
+// keep the n numerically largest tokens in descending order; those that are not numbers come last
+fn top_numeric(tokens: &mut Vec<String>, n: usize) {
+    let value = |token: &String| token.trim().parse::<f64>().unwrap_or(f64::NEG_INFINITY);
+    tokens.sort_by(|a, b| value(b).total_cmp(&value(a)));
+    tokens.truncate(n);
+}
+
+#[test]
+fn test_top_numeric() {
+    let mut tokens: Vec<String> = ["3", "x", "10", "-1", "7"].map(String::from).to_vec();
+    top_numeric(&mut tokens, 3);
+    assert_eq!(tokens, ["10", "7", "3"]);
+    top_numeric(&mut tokens, 5);
+    assert_eq!(tokens, ["10", "7", "3"]);
+}
+
+// the output fields of the group: the grouped output of each value column,
+// preceded by the number of records in the group with `-c`
+fn reduce(key: &str, columns: Vec<Tokens>, program_option: &ProgramOption) -> Vec<String> {
+    let count = columns.first().map_or(0, |tokens| tokens.seen);
+    let mut fields = Vec::new();
+    if program_option.count || program_option.count_only {
+        fields.push(count.to_string());
+    }
+    if program_option.count_only {
+        return fields;
+    }
+    for tokens in columns {
+        let value = reduce_column(key, tokens, program_option);
+        match program_option.aggregate {
+            // one field for each statistic
+            Some(Aggregate::Stats) => fields.extend(value.split('\t').map(str::to_owned)),
+            _ => fields.push(value),
+        }
+    }
+    fields
+}
+
+// a number as printed by `aggregate`, or null if undefined
+fn json_number(s: &str) -> &str {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() => s,
+        _ => "null",
+    }
+}
+
+#[test]
+fn test_json() {
+    assert_eq!(json_number("1.5"), "1.5");
+    assert_eq!(json_number(""), "null");
+    assert_eq!(json_number("NaN"), "null");
+}
+
+// the group as a JSON object, e.g., `{"key": "x", "values": ["a","b"], "count": 2}`;
+// with `-a`, the values are replaced by the aggregate, and with several value fields,
+// each column becomes an element of an array
+fn reduce_json(key: &str, columns: Vec<Tokens>, program_option: &ProgramOption) -> String {
+    let count = columns.first().map_or(0, |tokens| tokens.seen);
+    let mut fields = vec![format!("\"key\": {}", json_string(key))];
+    if !program_option.count_only {
+        let num_columns = columns.len();
+        let values: Vec<String> = columns
+            .into_iter()
+            .map(|tokens| {
+                let tokens = select_tokens(tokens, program_option);
+                match program_option.aggregate {
+                    None => {
+                        let tokens: Vec<String> = tokens.iter().map(|x| json_string(x)).collect();
+                        format!("[{}]", tokens.join(","))
+                    }
+                    Some(Aggregate::Stats) => {
+                        let stats = aggregate(key, &tokens, Aggregate::Stats);
+                        let names = ["count", "sum", "mean", "min", "max", "stddev"];
+                        let stats: Vec<String> = names
+                            .iter()
+                            .zip(stats.split('\t'))
+                            .map(|(name, x)| format!("\"{}\": {}", name, json_number(x)))
+                            .collect();
+                        format!("{{{}}}", stats.join(", "))
+                    }
+                    Some(x @ (Aggregate::First | Aggregate::Last)) => match tokens.is_empty() {
+                        true => "null".to_owned(),
+                        false => json_string(&aggregate(key, &tokens, x)),
+                    },
+                    Some(x) => json_number(&aggregate(key, &tokens, x)).to_owned(),
+                }
+            })
+            .collect();
+        let name = match program_option.aggregate {
+            None => "values".to_owned(),
+            Some(x) => aggregate_name(x),
+        };
+        let value = match num_columns {
+            1 => values.join(""),
+            _ => format!("[{}]", values.join(",")),
+        };
+        fields.push(format!("\"{}\": {}", name, value));
+    }
+    fields.push(format!("\"count\": {}", count));
+    format!("{{{}}}", fields.join(", "))
+}
+
+// the values collected for a key; with `--nest`, the values of each nested key instead,
+// in the order the nested keys first appear
+#[derive(Default)]
+struct Group {
+    columns: Vec<Tokens>,
+    nested: Vec<(String, Vec<Tokens>)>,
+    index: HashMap<String, usize>,
+}
+
+impl Group {
+    // the number of records
+    fn size(&self) -> usize {
+        let size = |columns: &Vec<Tokens>| columns.first().map_or(0, |tokens| tokens.seen);
+        size(&self.columns)
+            + self
+                .nested
+                .iter()
+                .map(|(_, columns)| size(columns))
+                .sum::<usize>()
+    }
+
+    // add the values of a line, whose nested key follows the values with `--nest`
+    fn push(&mut self, mut values: Vec<Cow<str>>, program_option: &ProgramOption) {
+        let columns = match program_option.nest_idx.and_then(|_| values.pop()) {
+            None => &mut self.columns,
+            Some(nested_key) => {
+                let idx = match self.index.get(nested_key.as_ref()) {
+                    Some(&idx) => idx,
+                    None => {
+                        self.index.insert(nested_key.to_string(), self.nested.len());
+                        self.nested.push((nested_key.into_owned(), Vec::new()));
+                        self.nested.len() - 1
+                    }
+                };
+                &mut self.nested[idx].1
+            }
+        };
+        push_values(columns, &values, program_option);
+    }
+
+    // append the records of a later part of the same group
+    fn merge(&mut self, other: Group, program_option: &ProgramOption) {
+        merge_columns(&mut self.columns, other.columns, program_option);
+        for (nested_key, columns) in other.nested {
+            let idx = match self.index.get(&nested_key) {
+                Some(&idx) => idx,
+                None => {
+                    self.index.insert(nested_key.clone(), self.nested.len());
+                    self.nested.push((nested_key, Vec::new()));
+                    self.nested.len() - 1
+                }
+            };
+            merge_columns(&mut self.nested[idx].1, columns, program_option);
+        }
+    }
+
+    // estimated bytes of the group
+    fn memory(&self) -> usize {
+        let memory = |columns: &Vec<Tokens>| columns.iter().map(Tokens::memory).sum::<usize>();
+        memory(&self.columns)
+            + self
+                .nested
+                .iter()
+                .map(|(nested_key, columns)| 2 * nested_key.len() + memory(columns))
+                .sum::<usize>()
+    }
+}
+
+fn merge_columns(columns: &mut Vec<Tokens>, other: Vec<Tokens>, program_option: &ProgramOption) {
+    columns.resize_with(columns.len().max(other.len()), Tokens::default);
+    for (tokens, other) in columns.iter_mut().zip(other) {
+        tokens.merge(other, program_option);
+    }
+}
+
+// write the group, unless it is smaller than `--min-size`
+fn write_group(
+    ofs: &mut impl Write,
+    key: &str,
+    group: Group,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    if let Some(summary) = &program_option.summary {
+        *summary.lock().unwrap().entry(group.size()).or_default() += 1;
+        return Ok(());
+    }
+    if group.size() >= program_option.min_size {
+        return write_group_to(ofs, key, group, program_option);
+    }
+    match &program_option.small_groups {
+        Some(small_groups) => {
+            let mut small_groups = small_groups.lock().unwrap();
+            write_group_to(&mut *small_groups, key, group, program_option)
+        }
+        None => Ok(()),
+    }
+}
+
+fn write_group_to(
+    ofs: &mut impl Write,
+    key: &str,
+    group: Group,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    if program_option.nest_idx.is_some() {
+        return write_nested(ofs, key, group.nested, program_option);
+    }
+    let columns = group.columns;
+    if program_option.json {
+        let json = reduce_json(key, columns, program_option);
+        return write!(ofs, "{}{}", json, program_option.record_delim);
+    }
+    write!(ofs, "{}", key)?;
+    for field in reduce(key, columns, program_option) {
+        write!(
+            ofs,
+            "{}{}",
+            program_option.output_delim,
+            quote(&field, program_option)
+        )?;
+    }
+    write!(ofs, "{}", program_option.record_delim)
+}
+
+// the field quoted as necessary with `--csv`
+fn quote<'a>(field: &'a str, program_option: &ProgramOption) -> Cow<'a, str> {
+    match program_option.csv {
+        true => quote_csv(field, &program_option.output_delim),
+        false => Cow::Borrowed(field),
+    }
+}
+
+// the size, the number of groups and the number of records in them for each size of groups,
+// and then for all the groups
+fn write_summary(
+    ofs: &mut impl Write,
+    summary: BTreeMap<usize, usize>,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    let (mut total_groups, mut total_records) = (0, 0);
+    for (size, groups) in summary {
+        let row = [
+            size.to_string(),
+            groups.to_string(),
+            (size * groups).to_string(),
+        ];
+        write!(
+            ofs,
+            "{}{}",
+            row.join(&program_option.output_delim),
+            program_option.record_delim
+        )?;
+        total_groups += groups;
+        total_records += size * groups;
+    }
+    let row = [
+        "total".to_owned(),
+        total_groups.to_string(),
+        total_records.to_string(),
+    ];
+    write!(
+        ofs,
+        "{}{}",
+        row.join(&program_option.output_delim),
+        program_option.record_delim
+    )
+}
+
+// the rows of `--pivot`, which are printed once all the columns are known
+#[derive(Default)]
+struct Pivot {
+    key_name: String,
+    rows: Vec<(String, Vec<(String, String)>)>, // key, (column, cell)
+}
+
+fn write_pivot(
+    ofs: &mut impl Write,
+    pivot: Pivot,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    let mut columns: Vec<&str> = pivot
+        .rows
+        .iter()
+        .flat_map(|(_, cells)| cells.iter().map(|(column, _)| column.as_str()))
+        .collect();
+    columns.sort();
+    columns.dedup();
+    let index: HashMap<&str, usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(idx, &column)| (column, idx))
+        .collect();
+
+    write!(ofs, "{}", pivot.key_name)?;
+    for column in &columns {
+        write!(
+            ofs,
+            "{}{}",
+            program_option.output_delim,
+            quote(column, program_option)
+        )?;
+    }
+    write!(ofs, "{}", program_option.record_delim)?;
+    for (key, cells) in &pivot.rows {
+        let mut row = vec![program_option.fill.as_str(); columns.len()];
+        for (column, cell) in cells {
+            row[index[column.as_str()]] = cell;
+        }
+        write!(ofs, "{}", key)?;
+        for cell in row {
+            write!(
+                ofs,
+                "{}{}",
+                program_option.output_delim,
+                quote(cell, program_option)
+            )?;
+        }
+        write!(ofs, "{}", program_option.record_delim)?;
+    }
+    Ok(())
+}
+
+// each nested group as a field of its key, a colon and its own fields separated by colons,
+// or with `--json`, as the objects of the `groups` array
+fn write_nested(
+    ofs: &mut impl Write,
+    key: &str,
+    nested: Vec<(String, Vec<Tokens>)>,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    if let Some(pivot) = &program_option.pivot {
+        let cells = nested
+            .into_iter()
+            .map(|(nested_key, columns)| {
+                let fields = reduce(&nested_key, columns, program_option);
+                (nested_key, fields.join(":"))
+            })
+            .collect();
+        pivot.lock().unwrap().rows.push((key.to_owned(), cells));
+        return Ok(());
+    }
+    if program_option.json {
+        let count: usize = nested
+            .iter()
+            .map(|(_, columns)| columns.first().map_or(0, |tokens| tokens.seen))
+            .sum();
+        let groups: Vec<String> = nested
+            .into_iter()
+            .map(|(nested_key, columns)| reduce_json(&nested_key, columns, program_option))
+            .collect();
+        return write!(
+            ofs,
+            "{{\"key\": {}, \"groups\": [{}], \"count\": {}}}{}",
+            json_string(key),
+            groups.join(", "),
+            count,
+            program_option.record_delim
+        );
+    }
+    write!(ofs, "{}", key)?;
+    for (nested_key, columns) in nested {
+        let fields = reduce(&nested_key, columns, program_option);
+        let field = format!("{}:{}", nested_key, fields.join(":"));
+        write!(
+            ofs,
+            "{}{}",
+            program_option.output_delim,
+            quote(&field, program_option)
+        )?;
+    }
+    write!(ofs, "{}", program_option.record_delim)
+}
+
+// the number of the first values of each group to keep, if not all
+fn value_limit(program_option: &ProgramOption) -> Option<usize> {
+    match program_option.aggregate {
+        Some(Aggregate::First) => Some(1),
+        _ => program_option.top.or(program_option.max_size),
+    }
+}
+
+// add the values of a line to the columns of its group
+fn push_values(columns: &mut Vec<Tokens>, values: &[Cow<str>], program_option: &ProgramOption) {
+    columns.resize_with(values.len(), Tokens::default);
+    for (tokens, value) in columns.iter_mut().zip(values) {
+        if !program_option.merge {
+            push_value(tokens, value, program_option);
+            continue;
+        }
+        // the value is already grouped
+        let mut parts = split_tokens(value, program_option);
+        if program_option.rle {
+            parts = expand_counts(parts, "*");
+        }
+        for part in parts {
+            push_value(tokens, &part, program_option);
+        }
+    }
+}
+
+fn push_value(tokens: &mut Tokens, value: &str, program_option: &ProgramOption) {
+    match (program_option.count_only, value_limit(program_option)) {
+        (true, _) => tokens.seen += 1, // no need to keep the values
+        (false, Some(n)) if !program_option.by_numeric && tokens.tokens.len() >= n => {
+            tokens.seen += 1 // only the first n are kept
+        }
+        (false, _) if program_option.aggregate == Some(Aggregate::Last) => {
+            tokens.tokens.clear();
+            tokens.push(value, None);
+        }
+        _ => {
+            tokens.push(value, program_option.sample);
+            // prune from time to time, so that the memory stays O(n)
+            if let (Some(n), true) = (program_option.top, program_option.by_numeric) {
+                if tokens.tokens.len() >= 2 * n.max(1) {
+                    top_numeric(&mut tokens.tokens, n);
+                }
+            }
+        }
+    }
+}
+
+// the group of each key along with the line number where the key first appeared
+type GroupMap = HashMap<String, (usize, Group)>;
+
+// the groups of the hashmap mode; with `--max-memory`, the largest groups are spilled to
+// a temporary file when the groups outgrow it, and merged back when they are written
+#[derive(Default)]
+struct Groups {
+    map: GroupMap,
+    memory: usize,                               // estimated bytes of the map
+    spilled: HashMap<String, (usize, Vec<u64>)>, // first line number, offsets of the parts
+    spill_file: Option<File>,
+}
+
+impl Groups {
+    fn push_line(
+        &mut self,
+        linenum: usize,
+        line: &str,
+        program_option: &ProgramOption,
+    ) -> io::Result<()> {
+        let (key, values) = match key_value(line, program_option) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let (_, group) = self
+            .map
+            .entry(key.into_owned())
+            .or_insert((linenum, Group::default()));
+        group.push(values, program_option);
+
+        self.memory += line.len() + std::mem::size_of::<String>();
+        match program_option.on_memory_limit {
+            Some(_) if self.memory > program_option.max_memory / program_option.threads => {
+                self.shrink(linenum, program_option)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // measure the groups, and if they indeed take more than the budget, either fail or
+    // spill the largest groups until they take half of it
+    fn shrink(&mut self, linenum: usize, program_option: &ProgramOption) -> io::Result<()> {
+        let budget = program_option.max_memory / program_option.threads;
+        let mut sizes: Vec<(usize, String)> = self
+            .map
+            .iter()
+            .map(|(key, (_, group))| (entry_memory(key, group), key.clone()))
+            .collect();
+        self.memory = sizes.iter().map(|(size, _)| size).sum();
+        if self.memory <= budget {
+            return Ok(());
+        }
+        if program_option.on_memory_limit == Some(OnMemoryLimit::Abort) {
+            let msg = format!(
+                "line {}: the groups take more than `--max-memory` of {} bytes; \
+                set `--spill` to spill the largest groups to temporary files",
+                linenum + program_option.header as usize + 1,
+                program_option.max_memory
+            );
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory, msg));
+        }
+
+        sizes.sort_by(|a, b| b.cmp(a));
+        for (size, key) in sizes {
+            if self.memory <= budget / 2 {
+                break;
+            }
+            let (first, group) = self.map.remove(&key).unwrap();
+            self.spill(key, first, group, program_option)?;
+            self.memory -= size;
+        }
+        Ok(())
+    }
+
+    fn spill(
+        &mut self,
+        key: String,
+        first: usize,
+        group: Group,
+        program_option: &ProgramOption,
+    ) -> io::Result<()> {
+        let file = match &mut self.spill_file {
+            Some(file) => file,
+            None => self.spill_file.insert(temp_file(&program_option.tmpdir)?),
+        };
+        let mut buffer = Vec::new();
+        write_group_state(&mut buffer, &group)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(&buffer)?;
+        let (_, offsets) = self.spilled.entry(key).or_insert((first, Vec::new()));
+        offsets.push(offset);
+        Ok(())
+    }
+
+    // the group of the key with its spilled parts merged back, in the order they came in
+    fn unspill(
+        &self,
+        key: &str,
+        group: Group,
+        program_option: &ProgramOption,
+    ) -> io::Result<Group> {
+        let (mut file, offsets) = match (&self.spill_file, self.spilled.get(key)) {
+            (Some(file), Some((_, offsets))) => (file, offsets),
+            _ => return Ok(group),
+        };
+        let mut merged = Group::default();
+        for &offset in offsets {
+            file.seek(SeekFrom::Start(offset))?;
+            merged.merge(read_group_state(&mut BufReader::new(file))?, program_option);
+        }
+        merged.merge(group, program_option);
+        Ok(merged)
+    }
+}
+
+// estimated bytes that a key and its group take in the map
+fn entry_memory(key: &str, group: &Group) -> usize {
+    key.len() + std::mem::size_of::<(String, (usize, Group))>() + group.memory()
+}
+
+// spilled groups consist of numbers and length-prefixed strings, so the tokens may contain
+// any delimiter
+fn write_number(ofs: &mut impl Write, number: usize) -> io::Result<()> {
+    ofs.write_all(&(number as u64).to_le_bytes())
+}
+
+fn read_number(ifs: &mut impl Read) -> io::Result<usize> {
+    let mut bytes = [0; 8];
+    ifs.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes) as usize)
+}
+
+fn write_string(ofs: &mut impl Write, string: &str) -> io::Result<()> {
+    write_number(ofs, string.len())?;
+    ofs.write_all(string.as_bytes())
+}
+
+fn read_string(ifs: &mut impl Read) -> io::Result<String> {
+    let mut bytes = vec![0; read_number(ifs)?];
+    ifs.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_columns(ofs: &mut impl Write, columns: &[Tokens]) -> io::Result<()> {
+    write_number(ofs, columns.len())?;
+    for tokens in columns {
+        write_number(ofs, tokens.seen)?;
+        write_number(ofs, tokens.tokens.len())?;
+        for token in &tokens.tokens {
+            write_string(ofs, token)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_columns(ifs: &mut impl Read) -> io::Result<Vec<Tokens>> {
+    (0..read_number(ifs)?)
+        .map(|_| {
+            let seen = read_number(ifs)?;
+            let tokens = (0..read_number(ifs)?)
+                .map(|_| read_string(ifs))
+                .collect::<io::Result<_>>()?;
+            Ok(Tokens {
+                tokens,
+                seen,
+                rng: Rng::default(),
+            })
+        })
+        .collect()
+}
+
+fn write_group_state(ofs: &mut impl Write, group: &Group) -> io::Result<()> {
+    write_columns(ofs, &group.columns)?;
+    write_number(ofs, group.nested.len())?;
+    for (nested_key, columns) in &group.nested {
+        write_string(ofs, nested_key)?;
+        write_columns(ofs, columns)?;
+    }
+    Ok(())
+}
+
+fn read_group_state(ifs: &mut impl Read) -> io::Result<Group> {
+    let mut group = Group {
+        columns: read_columns(ifs)?,
+        ..Group::default()
+    };
+    for _ in 0..read_number(ifs)? {
+        let nested_key = read_string(ifs)?;
+        group.index.insert(nested_key.clone(), group.nested.len());
+        group.nested.push((nested_key, read_columns(ifs)?));
+    }
+    Ok(group)
+}
+
+#[test]
+fn test_group_state() {
+    let mut group = Group::default();
+    group.columns.push(Tokens {
+        tokens: vec!["a\tb".to_owned(), "c\n".to_owned(), "".to_owned()],
+        seen: 5,
+        rng: Rng::default(),
+    });
+    group
+        .nested
+        .push(("x:y".to_owned(), vec![Tokens::default()]));
+    let mut buffer = Vec::new();
+    write_group_state(&mut buffer, &group).unwrap();
+    let state = read_group_state(&mut buffer.as_slice()).unwrap();
+    assert_eq!(state.columns[0].tokens, group.columns[0].tokens);
+    assert_eq!(state.columns[0].seen, 5);
+    assert_eq!(state.nested[0].0, "x:y");
+    assert_eq!(state.index["x:y"], 0);
+}
+
+// lines sent to a worker thread at a time
+const BATCH_SIZE: usize = 1024;
+
+// group in a thread per shard of the keys, so that the groups need no merging
+fn group_sharded<R: BufRead>(ifs: R, program_option: &ProgramOption) -> io::Result<Vec<Groups>> {
+    let hasher = RandomState::new();
+    let threads = program_option.threads;
+    thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..threads)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel::<Vec<(usize, String)>>(threads);
+                let worker = scope.spawn(move || {
+                    let mut groups = Groups::default();
+                    for batch in receiver {
+                        for (linenum, line) in batch {
+                            groups.push_line(linenum, &line, program_option)?;
+                        }
+                    }
+                    Ok(groups)
+                });
+                (sender, worker)
+            })
+            .unzip();
+
+        let mut batches = vec![Vec::with_capacity(BATCH_SIZE); threads];
+        for (linenum, line) in records(ifs, program_option).enumerate() {
+            let line = line?;
+            let shard = match key_value(&line, program_option) {
+                Some((key, _)) => hasher.hash_one(key) as usize % threads,
+                None => continue,
+            };
+            batches[shard].push((linenum, line));
+            if batches[shard].len() >= BATCH_SIZE {
+                let batch = std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+                if senders[shard].send(batch).is_err() {
+                    break; // the worker failed; its error is returned below
+                }
+            }
+        }
+        for (sender, batch) in senders.into_iter().zip(batches) {
+            let _ = sender.send(batch);
+        }
+
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+fn group_hashmap<R: BufRead, W: Write>(
+    ifs: R,
+    mut ofs: W,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    let mut shards = match program_option.threads {
+        1 => {
+            let mut groups = Groups::default();
+            for (linenum, line) in records(ifs, program_option).enumerate() {
+                groups.push_line(linenum, &line?, program_option)?;
+            }
+            vec![groups]
+        }
+        _ => group_sharded(ifs, program_option)?,
+    };
+
+    // (key, first line number, shard, the part of the group that is not spilled)
+    let mut groups = Vec::<(String, usize, usize, Group)>::new();
+    for (idx, shard) in shards.iter_mut().enumerate() {
+        for (key, (first, _)) in &shard.spilled {
+            shard.map.entry(key.clone()).or_default().0 = *first;
+        }
+        groups.extend(
+            shard
+                .map
+                .drain()
+                .map(|(key, (first, group))| (key, first, idx, group)),
+        );
+    }
+    if program_option.numeric {
+        groups.sort_by(|(a, ..), (b, ..)| compare_numeric(a, b));
+    } else if program_option.sort_keys {
+        groups.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+    } else if program_option.stable {
+        groups.sort_by_key(|(_, first, ..)| *first);
+    }
+
+    for (key, _, idx, group) in groups {
+        let group = shards[idx].unspill(&key, group, program_option)?;
+        write_group(&mut ofs, &key, group, program_option)?;
+    }
+
+    Ok(())
+}
+
+type Lines<'a> = Box<dyn Iterator<Item = io::Result<String>> + 'a>;
+
+// the records of the input, terminated by newline, or by NUL with `-z`;
+// with `--on-missing error`, those that lack fields fail
+fn records<'a>(ifs: impl BufRead + 'a, program_option: &ProgramOption) -> Lines<'a> {
+    let records: Lines = match program_option.record_delim {
+        '\0' => Box::new(ifs.split(b'\0').map(|record| {
+            String::from_utf8(record?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })),
+        _ => Box::new(ifs.lines()),
+    };
+    if program_option.on_missing != OnMissing::Error {
+        return records;
+    }
+    let last = last_field(program_option);
+    let delim = program_option.field_delim.clone();
+    let csv = program_option.csv;
+    let header = program_option.header as usize;
+    Box::new(records.enumerate().map(move |(linenum, record)| {
+        let record = record?;
+        let num_fields = match csv {
+            true => split_csv(&record, &delim).len(),
+            false => record.split(delim.as_str()).take(last + 1).count(),
+        };
+        match num_fields > last {
+            true => Ok(record),
+            false => {
+                let msg = format!(
+                    "line {}: expected at least {} fields, found {}",
+                    linenum + header + 1,
+                    last + 1,
+                    num_fields
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+            }
+        }
+    }))
+}
+
+// lines sorted by key, merged from the runs that are each sorted by key;
+// lines of the same key come in the order of the runs, and within a run
+struct MergedRuns<'a> {
+    runs: Vec<Lines<'a>>,
+    heap: BinaryHeap<Reverse<(String, usize, String)>>, // (key, run index, line)
+    program_option: &'a ProgramOption,
+}
+
+impl<'a> MergedRuns<'a> {
+    fn new(runs: Vec<Lines<'a>>, program_option: &'a ProgramOption) -> io::Result<Self> {
+        let mut merged = MergedRuns {
+            runs,
+            heap: BinaryHeap::new(),
+            program_option,
+        };
+        for idx in 0..merged.runs.len() {
+            merged.advance(idx)?;
+        }
+        Ok(merged)
+    }
+
+    // push the next line of the run to the heap
+    fn advance(&mut self, idx: usize) -> io::Result<()> {
+        if let Some(line) = self.runs[idx].next() {
+            let line = line?;
+            if let Some((key, _)) = key_value(&line, self.program_option) {
+                let key = key.into_owned();
+                self.heap.push(Reverse((key, idx, line)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergedRuns<'_> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, idx, line)) = self.heap.pop()?;
+        match self.advance(idx) {
+            Ok(()) => Some(Ok(line)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// sort the lines by key in runs of about max_memory bytes; all but the last
+// run are spilled to temporary files
+fn external_sort<'a, R: BufRead + 'a>(
+    ifs: R,
+    program_option: &'a ProgramOption,
+) -> io::Result<MergedRuns<'a>> {
+    let mut runs: Vec<Lines> = Vec::new();
+    let mut sorted = Vec::<(String, String)>::new();
+    let mut memory = 0;
+
+    for line in records(ifs, program_option) {
+        let line = line?;
+        let key = match key_value(&line, program_option) {
+            Some((key, _)) => key.into_owned(),
+            None => continue,
+        };
+        memory += key.len() + line.len() + std::mem::size_of::<(String, String)>();
+        sorted.push((key, line));
+        if memory >= program_option.max_memory {
+            sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let mut file = temp_file(&program_option.tmpdir)?;
+            let mut writer = BufWriter::new(&mut file);
+            for (_, line) in sorted.drain(..) {
+                write!(writer, "{}{}", line, program_option.record_delim)?;
+            }
+            writer.flush()?;
+            drop(writer);
+            file.rewind()?;
+            runs.push(records(BufReader::new(file), program_option));
+            memory = 0;
+        }
+    }
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+    runs.push(Box::new(sorted.into_iter().map(|(_, line)| Ok(line))));
+
+    MergedRuns::new(runs, program_option)
+}
+
+// detects keys that reappear after other keys, which sorted input never has
+#[derive(Default)]
+struct SortCheck {
+    current: Option<String>,
+    finished: HashSet<String>,
+}
+
+impl SortCheck {
+    fn check(&mut self, key: &str) -> bool {
+        if Some(key) == self.current.as_deref() {
+            return true;
+        }
+        if let Some(current) = self.current.take() {
+            self.finished.insert(current);
+        }
+        self.current = Some(key.to_owned());
+        !self.finished.contains(key)
+    }
+}
+
+#[test]
+fn test_sort_check() {
+    let mut check = SortCheck::default();
+    assert!(["b", "b", "a", "c", "c"].iter().all(|key| check.check(key)));
+    assert!(!check.check("a"));
+}
+
+fn unsorted_error(linenum: usize, key: &str) -> String {
+    format!(
+        "line {}: key `{}` reappears after other keys; the input is not sorted",
+        linenum + 1,
+        key
+    )
+}
+
+// the first line whose key reappears after other keys, if any
+fn find_unsorted(ifs: impl BufRead, program_option: &ProgramOption) -> io::Result<Option<String>> {
+    let mut check = SortCheck::default();
+    for (linenum, line) in records(ifs, program_option).enumerate() {
+        let line = line?;
+        if let Some((key, _)) = key_value(&line, program_option) {
+            if !check.check(&key) {
+                let linenum = linenum + program_option.header as usize;
+                return Ok(Some(unsorted_error(linenum, &key)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn group<W: Write>(
+    lines: impl Iterator<Item = io::Result<String>>,
+    mut ofs: W,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    let mut prev_key = Option::<String>::None;
+    let mut current = Group::default();
+    let mut check = program_option.check_sorted.map(|_| SortCheck::default());
+
+    for (linenum, line) in lines.enumerate() {
+        let line = line?;
+        let (key, values) = match key_value(&line, program_option) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let Some(check) = check.as_mut() {
+            if !check.check(&key) {
+                ofs.flush()?;
+                let linenum = linenum + program_option.header as usize;
+                let msg = unsorted_error(linenum, &key) + "; set `-m` for unsorted input";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        }
+        if Some(key.as_ref()) != prev_key.as_deref() {
+            if let Some(prev_key) = prev_key {
+                write_group(
+                    &mut ofs,
+                    &prev_key,
+                    std::mem::take(&mut current),
+                    program_option,
+                )?;
+            }
+            prev_key = Some(key.into_owned());
+        }
+        current.push(values, program_option);
+    }
+
+    match prev_key {
+        Some(prev_key) => write_group(&mut ofs, &prev_key, current, program_option),
+        None => Ok(()), // empty input
+    }
+}
+
+// the groups of the lines sorted by key, one at a time
+struct SortedGroups<'a> {
+    lines: Lines<'a>,
+    pending: Option<String>, // the first line of the next group
+    program_option: &'a ProgramOption,
+}
+
+impl<'a> SortedGroups<'a> {
+    fn new(lines: Lines<'a>, program_option: &'a ProgramOption) -> Self {
+        SortedGroups {
+            lines,
+            pending: None,
+            program_option,
+        }
+    }
+}
+
+impl Iterator for SortedGroups<'_> {
+    type Item = io::Result<(String, Group)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut current = Option::<String>::None;
+        let mut group = Group::default();
+        loop {
+            let line = match self.pending.take().map(Ok).or_else(|| self.lines.next()) {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e)),
+                None => break,
+            };
+            let (key, values) = match key_value(&line, self.program_option) {
+                Some(x) => x,
+                None => continue,
+            };
+            match &current {
+                Some(current) if current != key.as_ref() => {
+                    self.pending = Some(line);
+                    break;
+                }
+                Some(_) => {}
+                None => current = Some(key.to_string()),
+            }
+            group.push(values, self.program_option);
+        }
+        current.map(|key| Ok((key, group)))
+    }
+}
+
+// the next group of a joined input, which must come in increasing order of keys
+fn next_group(
+    groups: &mut SortedGroups,
+    prev: Option<&(String, Group)>,
+    file: &str,
+) -> io::Result<Option<(String, Group)>> {
+    let group = groups.next().transpose()?;
+    if let (Some((prev, _)), Some((key, _))) = (prev, &group) {
+        if key < prev {
+            let msg = format!(
+                "{}: key `{}` comes after `{}`; `--join` requires input sorted by key",
+                file, key, prev
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+    }
+    Ok(group)
+}
+
+// the key followed by the output fields of the group of each input; a key missing from an
+// input has the fields of an empty group
+fn write_joined(
+    ofs: &mut impl Write,
+    key: &str,
+    groups: [Option<Group>; 2],
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    write!(ofs, "{}", key)?;
+    for group in groups {
+        let mut columns = group.map_or_else(Vec::new, |group| group.columns);
+        columns.resize_with(program_option.value_idx.len(), Tokens::default);
+        for field in reduce(key, columns, program_option) {
+            write!(
+                ofs,
+                "{}{}",
+                program_option.output_delim,
+                quote(&field, program_option)
+            )?;
+        }
+    }
+    write!(ofs, "{}", program_option.record_delim)
+}
+
+// join the groups of both inputs sorted by key in a single merge pass
+fn join(
+    lines: Lines,
+    other_lines: Lines,
+    ofs: &mut impl Write,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    let join = program_option.join.unwrap_or(Join::Inner);
+    let files = [&program_option.input_file, &program_option.join_file];
+    let mut groups = SortedGroups::new(lines, program_option);
+    let mut other_groups = SortedGroups::new(other_lines, program_option);
+    let mut left = next_group(&mut groups, None, files[0])?;
+    let mut right = next_group(&mut other_groups, None, files[1])?;
+
+    loop {
+        let order = match (&left, &right) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((a, _)), Some((b, _))) => a.cmp(b),
+        };
+        if order != Ordering::Greater {
+            let prev = left.take();
+            left = next_group(&mut groups, prev.as_ref(), files[0])?;
+            let (key, group) = prev.unwrap();
+            if order == Ordering::Equal {
+                let prev = right.take();
+                right = next_group(&mut other_groups, prev.as_ref(), files[1])?;
+                let (_, other) = prev.unwrap();
+                write_joined(ofs, &key, [Some(group), Some(other)], program_option)?;
+            } else if join != Join::Inner {
+                write_joined(ofs, &key, [Some(group), None], program_option)?;
+            }
+        } else {
+            let prev = right.take();
+            right = next_group(&mut other_groups, prev.as_ref(), files[1])?;
+            let (key, other) = prev.unwrap();
+            if join == Join::Full {
+                write_joined(ofs, &key, [None, Some(other)], program_option)?;
+            }
+        }
+    }
+}
+
+// group every n lines, keyed by the number of the batch
+fn group_batches<W: Write>(
+    lines: impl Iterator<Item = io::Result<String>>,
+    mut ofs: W,
+    n: usize,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    let mut batch = Group::default();
+    let mut number = 1;
+    for line in lines {
+        push_values(&mut batch.columns, &[Cow::Borrowed(&line?)], program_option);
+        if batch.size() == n {
+            let batch = std::mem::take(&mut batch);
+            write_group(&mut ofs, &number.to_string(), batch, program_option)?;
+            number += 1;
+        }
+    }
+    match batch.size() {
+        0 => Ok(()),
+        _ => write_group(&mut ofs, &number.to_string(), batch, program_option),
+    }
+}
+
+// the i-th tokens of the columns make up the i-th row; the shorter columns are padded
+fn zip_columns<T: Clone + Default>(columns: Vec<Vec<T>>) -> Vec<Vec<T>> {
+    let num_rows = columns.iter().map(Vec::len).max().unwrap_or(0);
+    (0..num_rows)
+        .map(|idx| {
+            columns
+                .iter()
+                .map(|tokens| tokens.get(idx).cloned().unwrap_or_default())
+                .collect()
+        })
+        .collect()
+}
+
+// every combination of a token from each column, varying the last column the fastest
+fn cross_product<T: Clone>(columns: Vec<Vec<T>>) -> Vec<Vec<T>> {
+    columns.into_iter().fold(vec![vec![]], |rows, tokens| {
+        rows.iter()
+            .flat_map(|row| {
+                tokens.iter().map(move |token| {
+                    let mut row = row.clone();
+                    row.push(token.clone());
+                    row
+                })
+            })
+            .collect()
+    })
+}
+
+#[test]
+fn test_explode() {
+    let columns = vec![vec!["a", "b"], vec!["1", "2", "3"]];
+    assert_eq!(
+        zip_columns(columns.clone()),
+        [["a", "1"], ["b", "2"], ["", "3"]]
+    );
+    assert_eq!(
+        cross_product(columns),
+        [
+            ["a", "1"],
+            ["a", "2"],
+            ["a", "3"],
+            ["b", "1"],
+            ["b", "2"],
+            ["b", "3"]
+        ]
+    );
+}
+
+fn ungroup<R: BufRead, W: Write>(
+    ifs: R,
+    mut ofs: W,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    for line in records(ifs, program_option) {
+        let line = line?;
+        let (key, values) = match key_value(&line, program_option) {
+            Some(x) => x,
+            None => continue,
+        };
+        let columns: Vec<Vec<Cow<str>>> = values
+            .iter()
+            .map(|value| {
+                let tokens = split_tokens(value, program_option);
+                match (&program_option.weighted, program_option.rle) {
+                    (Some(delim), _) => expand_counts(tokens, delim),
+                    (None, true) => expand_counts(tokens, "*"),
+                    (None, false) => tokens,
+                }
+            })
+            .collect();
+        let mut rows = match program_option.cross {
+            true => cross_product(columns),
+            false => zip_columns(columns),
+        };
+        if program_option.unique && program_option.stable_unique {
+            rows = count_stable(rows).into_iter().map(|(row, _)| row).collect();
+        } else if program_option.unique {
+            rows.sort();
+            rows.dedup();
+        }
+        for (idx, row) in rows.into_iter().enumerate() {
+            write!(ofs, "{}", key)?;
+            if program_option.enumerate {
+                write!(ofs, "{}{}", program_option.output_delim, idx + 1)?;
+            }
+            for token in row {
+                write!(
+                    ofs,
+                    "{}{}",
+                    program_option.output_delim,
+                    quote(&token, program_option)
+                )?;
+            }
+            write!(ofs, "{}", program_option.record_delim)?;
+        }
+    }
+    Ok(())
+}
+
+fn run(mut program_option: ProgramOption) -> io::Result<()> {
+    let output_file = "/dev/stdout".to_owned();
+
+    let file = File::open(&program_option.input_file)?;
+    let is_file = file.metadata()?.is_file();
+    let mut ifs = BufReader::new(file);
+    let mut ofs = BufWriter::new(File::create(output_file)?);
+
+    if program_option.header {
+        let header = records(&mut ifs, &program_option)
+            .next()
+            .transpose()?
+            .unwrap_or_default();
+        let names: Vec<Cow<str>> = match program_option.csv {
+            true => split_csv(&header, &program_option.field_delim),
+            false => header
+                .split(&program_option.field_delim)
+                .map(Cow::Borrowed)
+                .collect(),
+        };
+        resolve_fields(&mut program_option, &names)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+        if let Some(pivot) = &program_option.pivot {
+            let key: Vec<&str> = program_option
+                .key_idx
+                .iter()
+                .map(|&idx| names.get(idx).map_or("", Cow::as_ref))
+                .collect();
+            pivot.lock().unwrap().key_name = key.join(&program_option.field_delim);
+        } else if !program_option.json && program_option.summary.is_none() {
+            let header = output_header(&names, &program_option);
+            let record_delim = program_option.record_delim;
+            write!(ofs, "{}{}", header, record_delim)?;
+            if let Some(small_groups) = &program_option.small_groups {
+                write!(small_groups.lock().unwrap(), "{}{}", header, record_delim)?;
+            }
+        }
+    }
+
+    if program_option.check_sorted == Some(OnUnsorted::Fallback) {
+        if !is_file {
+            let msg = "`--check-sorted=fallback` requires a regular input file";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if let Some(msg) = find_unsorted(&mut ifs, &program_option)? {
+            eprintln!("{}; falling back to the hashmap", msg);
+            program_option.hashmap = true;
+            program_option.stable = true;
+        }
+        program_option.check_sorted = None;
+        ifs.rewind()?;
+        if program_option.header {
+            records(&mut ifs, &program_option).next().transpose()?;
+        }
+    }
+
+    match program_option.inverse {
+        false if program_option.join.is_some() => {
+            let other = BufReader::new(File::open(&program_option.join_file)?);
+            join(
+                records(ifs, &program_option),
+                records(other, &program_option),
+                &mut ofs,
+                &program_option,
+            )
+        }
+        false => match (program_option.hashmap, program_option.external) {
+            (true, _) => group_hashmap(ifs, &mut ofs, &program_option),
+            (false, true) => group(
+                external_sort(ifs, &program_option)?,
+                &mut ofs,
+                &program_option,
+            ),
+            (false, false) => match program_option.batch {
+                Some(n) => {
+                    group_batches(records(ifs, &program_option), &mut ofs, n, &program_option)
+                }
+                None => group(records(ifs, &program_option), &mut ofs, &program_option),
+            },
+        },
+        true => ungroup(ifs, &mut ofs, &program_option),
+    }?;
+    if let Some(summary) = program_option.summary.take() {
+        write_summary(&mut ofs, summary.into_inner().unwrap(), &program_option)?;
+    }
+    if let Some(pivot) = program_option.pivot.take() {
+        write_pivot(&mut ofs, pivot.into_inner().unwrap(), &program_option)?;
+    }
+    match &program_option.small_groups {
+        Some(small_groups) => small_groups.lock().unwrap().flush(),
+        None => Ok(()),
+    }
+}
+
+fn main() -> ExitCode {
+    let program_option = match parse_arguments() {
+        Err(ref msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::FAILURE;
+        }
+        Ok(x) => x,
+    };
+
+    match run(program_option) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
     }
 }
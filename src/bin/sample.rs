@@ -0,0 +1,358 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+use clap::Parser;
+use float_ord::FloatOrd;
+use suputils::random::{random_seed, Rng};
+
+#[derive(Parser)]
+#[command(name = "sample")]
+#[command(author = "TechHara")]
+#[command(version = "0.1.0")]
+#[command(
+    about = "Print a uniform or weighted random sample of the lines in the order they appear.
+It reads the input once and keeps only the sampled lines in memory.
+
+    $ cat input
+    cat	a
+    dog	b
+    cat	c
+    cat	d
+    dog	e
+    bird	f
+
+    # set `-n N` to sample N of all the lines; the sample differs on each run
+    # unless `--seed` is set
+    $ sample -n 3 --seed 1 input
+    cat	a
+    cat	d
+    bird	f
+
+    # set `--per-key N` to sample up to N lines for each distinct key in the `-k` field,
+    # e.g., for balanced samples of each class
+    $ sample --per-key 1 -k 1 --seed 1 input
+    cat	c
+    dog	e
+    bird	f
+
+    $ cat traffic
+    /home	900
+    /login	90
+    /about	9
+    /help	1
+
+    # set `--weight-field N` to sample the lines with probability proportional to
+    # the number in the Nth field
+    $ sample -n 2 --weight-field 2 --seed 1 traffic
+    /home	900
+    /login	90
+
+    # set `--replace` to sample with replacement, so a line may be sampled more than once
+    $ sample -n 3 --weight-field 2 --replace --seed 1 traffic
+    /home	900
+    /home	900
+    /home	900
+"
+)]
+struct Arguments {
+    /// Field delimiter character
+    #[arg(short = 't', default_value_t = '\t')]
+    field_delim: char,
+    /// with `--per-key`, the field of the key
+    #[arg(short = 'k', default_value_t = 1)]
+    key_field: usize,
+    /// sample N of all the lines
+    #[arg(short = 'n', value_name = "N", conflicts_with = "per_key")]
+    size: Option<usize>,
+    /// sample up to N lines for each distinct key
+    #[arg(long, value_name = "N")]
+    per_key: Option<usize>,
+    /// sample the lines with probability proportional to the non-negative number in this field
+    #[arg(long, value_name = "N")]
+    weight_field: Option<usize>,
+    /// sample with replacement, so each of the N lines is drawn independently of the others
+    #[arg(long)]
+    replace: bool,
+    /// seed of the random numbers, for the same sample on every run [default: random]
+    #[arg(long, value_name = "SEED")]
+    seed: Option<u64>,
+    /// Input file; If omitted, read from stdin
+    input: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Method {
+    Uniform,
+    Weighted,
+    Replace,
+}
+
+struct ProgramOption {
+    field_delim: String,
+    key_idx: Option<usize>,    // 0-index, with `--per-key`
+    weight_idx: Option<usize>, // 0-index
+    method: Method,
+    size: usize,
+    seed: u64,
+    input_file: String,
+}
+
+fn parse_arguments() -> Result<ProgramOption, String> {
+    let args = Arguments::parse();
+    let input_file = match args.input.is_some() && args.input != Some("-".to_owned()) {
+        true => args.input.unwrap(),
+        false => "/dev/stdin".to_owned(),
+    };
+
+    if args.key_field == 0 {
+        return Err("key field must be 1 or greater".to_owned());
+    }
+    if args.weight_field == Some(0) {
+        return Err("weight field must be 1 or greater".to_owned());
+    }
+    let (size, key_idx) = match (args.size, args.per_key) {
+        (Some(n), _) => (n, None),
+        (None, Some(n)) => (n, Some(args.key_field - 1)),
+        (None, None) => return Err("either -n or --per-key is required".to_owned()),
+    };
+    let method = match (args.replace, args.weight_field) {
+        (true, _) => Method::Replace,
+        (false, Some(_)) => Method::Weighted,
+        (false, None) => Method::Uniform,
+    };
+
+    Ok(ProgramOption {
+        field_delim: args.field_delim.to_string(),
+        key_idx,
+        weight_idx: args.weight_field.map(|field| field - 1),
+        method,
+        size,
+        seed: args.seed.unwrap_or_else(random_seed),
+        input_file,
+    })
+}
+
+// sample of the lines pushed, with their line numbers
+enum Reservoir {
+    // uniform, by Algorithm R
+    Uniform {
+        seen: usize,
+        lines: Vec<(usize, String)>,
+    },
+    // weighted, by A-Res: keep the lines of the largest keys u^(1/w), compared as ln(u)/w
+    Weighted(BinaryHeap<Reverse<(FloatOrd<f64>, usize, String)>>),
+    // with replacement: each line of the sample is a weighted reservoir of one line
+    Replace {
+        total: f64, // sum of the weights
+        lines: Vec<(usize, String)>,
+    },
+}
+
+impl Reservoir {
+    fn new(method: Method) -> Self {
+        match method {
+            Method::Uniform => Reservoir::Uniform {
+                seen: 0,
+                lines: Vec::new(),
+            },
+            Method::Weighted => Reservoir::Weighted(BinaryHeap::new()),
+            Method::Replace => Reservoir::Replace {
+                total: 0.0,
+                lines: Vec::new(),
+            },
+        }
+    }
+
+    // the weight is ignored by the uniform reservoir and must be positive otherwise
+    fn push(&mut self, linenum: usize, line: String, weight: f64, size: usize, rng: &mut Rng) {
+        match self {
+            Reservoir::Uniform { seen, lines } => {
+                *seen += 1;
+                if lines.len() < size {
+                    lines.push((linenum, line));
+                    return;
+                }
+                let idx = rng.below(*seen);
+                if idx < size {
+                    lines[idx] = (linenum, line);
+                }
+            }
+            Reservoir::Weighted(heap) => {
+                let key = FloatOrd(rng.uniform().ln() / weight);
+                if heap.len() < size {
+                    heap.push(Reverse((key, linenum, line)));
+                } else if heap.peek().is_some_and(|Reverse((min, ..))| key > *min) {
+                    heap.pop();
+                    heap.push(Reverse((key, linenum, line)));
+                }
+            }
+            Reservoir::Replace { total, lines } => {
+                *total += weight;
+                if lines.is_empty() {
+                    lines.resize(size, (linenum, line));
+                    return;
+                }
+                // each line of the sample is replaced with probability weight / total,
+                // so skip to the next one replaced instead of drawing for each
+                let probability = weight / *total;
+                let mut idx = rng.geometric(probability);
+                while idx < size {
+                    lines[idx] = (linenum, line.clone());
+                    idx = idx.saturating_add(1 + rng.geometric(probability));
+                }
+            }
+        }
+    }
+
+    fn into_lines(self) -> Vec<(usize, String)> {
+        match self {
+            Reservoir::Uniform { lines, .. } | Reservoir::Replace { lines, .. } => lines,
+            Reservoir::Weighted(heap) => heap
+                .into_iter()
+                .map(|Reverse((_, linenum, line))| (linenum, line))
+                .collect(),
+        }
+    }
+}
+
+// how often each of the lines 0, 1, 2, 3 with weights 1, 2, 3, 4 is sampled in 10000 trials
+#[cfg(test)]
+fn sample_hits(method: Method, size: usize) -> [usize; 4] {
+    let mut rng = Rng::new(0);
+    let mut hits = [0; 4];
+    for _ in 0..10000 {
+        let mut reservoir = Reservoir::new(method);
+        for x in 0..4 {
+            reservoir.push(x, x.to_string(), (x + 1) as f64, size, &mut rng);
+        }
+        let lines = reservoir.into_lines();
+        assert_eq!(lines.len(), size);
+        for (x, line) in lines {
+            assert_eq!(line, x.to_string());
+            hits[x] += 1;
+        }
+    }
+    hits
+}
+
+#[test]
+fn test_reservoir() {
+    let within = |hits: [usize; 4], expected: [usize; 4]| {
+        let tolerance = |x: usize| (x as f64 * 0.1) as usize;
+        hits.iter()
+            .zip(expected)
+            .all(|(&n, x)| n.abs_diff(x) <= tolerance(x))
+    };
+    // each line is sampled with probability 1/2
+    let hits = sample_hits(Method::Uniform, 2);
+    assert!(within(hits, [5000; 4]), "{:?}", hits);
+    // a single line is sampled with probability proportional to its weight
+    let hits = sample_hits(Method::Weighted, 1);
+    assert!(within(hits, [1000, 2000, 3000, 4000]), "{:?}", hits);
+    // without replacement, the lines sampled are distinct
+    let hits = sample_hits(Method::Weighted, 4);
+    assert_eq!(hits, [10000; 4]);
+    // with replacement, each sampled line is proportional to its weight
+    let hits = sample_hits(Method::Replace, 2);
+    assert!(within(hits, [2000, 4000, 6000, 8000]), "{:?}", hits);
+    // even if the sample is much larger than the input
+    let mut rng = Rng::new(0);
+    let mut reservoir = Reservoir::new(Method::Replace);
+    for x in 0..4 {
+        reservoir.push(x, x.to_string(), (x + 1) as f64, 100000, &mut rng);
+    }
+    let mut hits = [0; 4];
+    for (x, _) in reservoir.into_lines() {
+        hits[x] += 1;
+    }
+    assert!(within(hits, [10000, 20000, 30000, 40000]), "{:?}", hits);
+}
+
+fn run(
+    ifs: impl BufRead,
+    mut ofs: impl Write,
+    program_option: ProgramOption,
+) -> Result<(), String> {
+    let mut rng = Rng::new(program_option.seed);
+    let mut reservoirs = HashMap::<String, Reservoir>::new();
+    for (linenum, line) in ifs.lines().enumerate() {
+        let line = line.expect("failed to read");
+        let key = match program_option.key_idx {
+            Some(idx) => match line.split(&program_option.field_delim).nth(idx) {
+                Some(x) => x,
+                None => {
+                    eprintln!("{}: col {} does not exist; skipping", linenum + 1, idx + 1);
+                    continue;
+                }
+            },
+            None => "",
+        };
+        let weight = match program_option.weight_idx {
+            Some(idx) => match line.split(&program_option.field_delim).nth(idx) {
+                Some(x) => parse_weight(x).map_err(|msg| format!("{}: {}", linenum + 1, msg))?,
+                None => {
+                    eprintln!("{}: col {} does not exist; skipping", linenum + 1, idx + 1);
+                    continue;
+                }
+            },
+            None => 1.0,
+        };
+        // a line of zero weight is never sampled
+        if weight == 0.0 {
+            continue;
+        }
+        if !reservoirs.contains_key(key) {
+            reservoirs.insert(key.to_owned(), Reservoir::new(program_option.method));
+        }
+        let reservoir = reservoirs.get_mut(key).unwrap();
+        reservoir.push(linenum, line, weight, program_option.size, &mut rng);
+    }
+
+    let mut lines: Vec<(usize, String)> = reservoirs
+        .into_values()
+        .flat_map(Reservoir::into_lines)
+        .collect();
+    lines.sort_unstable_by_key(|(linenum, _)| *linenum);
+    for (_, line) in lines {
+        writeln!(ofs, "{}", line).expect("failed writing out");
+    }
+    Ok(())
+}
+
+fn parse_weight(weight: &str) -> Result<f64, String> {
+    match weight.trim().parse::<f64>() {
+        Ok(x) if x.is_finite() && x >= 0.0 => Ok(x),
+        _ => Err(format!(
+            "cannot parse `{}` as a non-negative weight",
+            weight
+        )),
+    }
+}
+
+fn main() -> ExitCode {
+    let program_option = match parse_arguments() {
+        Err(ref msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::FAILURE;
+        }
+        Ok(x) => x,
+    };
+
+    let output_file = "/dev/stdout".to_owned();
+
+    let ifs = BufReader::new(
+        File::open(program_option.input_file.clone()).expect("Error reading input file"),
+    );
+    let ofs = BufWriter::new(File::create(output_file).expect("Error writing to stdout"));
+
+    match run(ifs, ofs, program_option) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, Write};
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+use suputils::random::{random_seed, Rng};
+use suputils::{parse_size, temp_file};
+
+#[derive(Parser)]
+#[command(name = "shuffle")]
+#[command(author = "TechHara")]
+#[command(version = "0.1.0")]
+#[command(
+    about = "Print the lines in a uniformly random order, in bounded memory.
+The lines are shuffled in memory as long as they fit in `--max-memory`;
+otherwise, they are spread over temporary files at random, each of which is shuffled in turn,
+so that input much larger than the memory can be shuffled as well.
+
+    $ cat input
+    one
+    two
+    three
+    four
+    five
+
+    # the order is different on each run unless `--seed` is set
+    $ shuffle --seed 1 input
+    three
+    one
+    five
+    four
+    two
+
+    # shuffle huge input in 4G of memory, with the temporary files in /scratch
+    $ shuffle --max-memory 4G --tmpdir /scratch huge-input
+"
+)]
+struct Arguments {
+    /// seed of the random numbers, for the same order on every run [default: random]
+    #[arg(long, value_name = "SEED")]
+    seed: Option<u64>,
+    /// memory to shuffle in before spilling to temporary files, e.g., 512M or 4G [default: 256M]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// directory for the temporary files [default: system temp directory]
+    #[arg(long, value_name = "DIR")]
+    tmpdir: Option<PathBuf>,
+    /// Input file; If omitted, read from stdin
+    input: Option<String>,
+}
+
+struct ProgramOption {
+    seed: u64,
+    max_memory: usize,
+    tmpdir: PathBuf,
+    input_file: String,
+}
+
+fn parse_arguments() -> Result<ProgramOption, String> {
+    let args = Arguments::parse();
+    let input_file = match args.input.is_some() && args.input != Some("-".to_owned()) {
+        true => args.input.unwrap(),
+        false => "/dev/stdin".to_owned(),
+    };
+
+    Ok(ProgramOption {
+        seed: args.seed.unwrap_or_else(random_seed),
+        max_memory: args.max_memory.unwrap_or(MAX_MEMORY),
+        tmpdir: args.tmpdir.unwrap_or_else(std::env::temp_dir),
+        input_file,
+    })
+}
+
+// memory to shuffle in unless `--max-memory` is set
+const MAX_MEMORY: usize = 256 << 20;
+
+// number of temporary files that the lines are spread over whenever they do not fit in memory;
+// each of them is spread again if it still does not fit
+const FANOUT: usize = 64;
+
+type Lines<'a> = Box<dyn Iterator<Item = io::Result<String>> + 'a>;
+
+// Write the lines in a uniformly random order. Those that fit in max_memory are shuffled
+// in memory by Fisher-Yates; otherwise, each line is put in one of the temporary files
+// at random, and each file is shuffled in turn. Any order of the lines is equally likely
+// either way, since the lines of each file are equally likely to be any of the lines.
+fn shuffle(
+    lines: &mut Peekable<Lines>,
+    ofs: &mut impl Write,
+    rng: &mut Rng,
+    program_option: &ProgramOption,
+) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    let mut memory = 0;
+    // a line that alone takes more than max_memory is still shuffled in memory
+    while memory < program_option.max_memory || buffer.is_empty() {
+        match lines.next() {
+            Some(line) => {
+                let line = line?;
+                memory += line.len() + std::mem::size_of::<String>();
+                buffer.push(line);
+            }
+            None => break,
+        }
+    }
+    if lines.peek().is_none() {
+        for idx in (1..buffer.len()).rev() {
+            buffer.swap(idx, rng.below(idx + 1));
+        }
+        for line in buffer {
+            writeln!(ofs, "{}", line)?;
+        }
+        return Ok(());
+    }
+
+    // each file is created once a line is put in it
+    let mut files: Vec<Option<BufWriter<File>>> = (0..FANOUT).map(|_| None).collect();
+    for line in buffer.into_iter().map(Ok).chain(lines) {
+        let idx = rng.below(FANOUT);
+        let file = match &mut files[idx] {
+            Some(file) => file,
+            None => {
+                let file = temp_file(&program_option.tmpdir).map_err(|e| {
+                    let msg = format!("temporary file in {:?}: {}", program_option.tmpdir, e);
+                    io::Error::new(e.kind(), msg)
+                })?;
+                files[idx].insert(BufWriter::new(file))
+            }
+        };
+        writeln!(file, "{}", line?)?;
+    }
+    for file in files.into_iter().flatten() {
+        let mut file = file.into_inner().map_err(|e| e.into_error())?;
+        file.rewind()?;
+        let lines: Lines = Box::new(BufReader::new(file).lines());
+        shuffle(&mut lines.peekable(), ofs, rng, program_option)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_shuffle() {
+    let program_option = ProgramOption {
+        seed: 0,
+        max_memory: 1, // spread the lines over the temporary files until they are apart
+        tmpdir: std::env::temp_dir(),
+        input_file: String::new(),
+    };
+    let mut rng = Rng::new(program_option.seed);
+    let mut orders = std::collections::HashMap::new();
+    for _ in 0..6000 {
+        let lines: Lines = Box::new(["a", "b", "c"].into_iter().map(|x| Ok(x.to_owned())));
+        let mut output = Vec::new();
+        shuffle(
+            &mut lines.peekable(),
+            &mut output,
+            &mut rng,
+            &program_option,
+        )
+        .unwrap();
+        *orders.entry(output).or_insert(0) += 1;
+    }
+    // each of the 6 orders is equally likely
+    assert_eq!(orders.len(), 6);
+    assert!(
+        orders.values().all(|n| (900..1100).contains(n)),
+        "{:?}",
+        orders
+    );
+}
+
+fn run(program_option: ProgramOption) -> Result<(), String> {
+    let ifs = BufReader::new(
+        File::open(&program_option.input_file)
+            .map_err(|e| format!("{}: {}", program_option.input_file, e))?,
+    );
+    let mut ofs = BufWriter::new(
+        File::create("/dev/stdout").map_err(|e| format!("Error writing to stdout: {}", e))?,
+    );
+
+    let mut rng = Rng::new(program_option.seed);
+    let lines: Lines = Box::new(ifs.lines());
+    shuffle(&mut lines.peekable(), &mut ofs, &mut rng, &program_option)
+        .and_then(|_| ofs.flush())
+        .map_err(|e| e.to_string())
+}
+
+fn main() -> ExitCode {
+    let program_option = match parse_arguments() {
+        Err(ref msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::FAILURE;
+        }
+        Ok(x) => x,
+    };
+
+    match run(program_option) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
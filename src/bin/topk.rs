@@ -138,7 +138,7 @@ impl<T: Ord> SelectK<T> for BottomK<T> {
 
     fn into_sorted_vector(mut self) -> Vec<T> {
         let mut result = Vec::with_capacity(self.len());
-        while !self.heap.is_empty() {
+        while !self.is_empty() {
             result.push(self.heap.pop().unwrap());
         }
         result.reverse();
@@ -186,7 +186,7 @@ impl<T: Ord> SelectK<T> for TopK<T> {
 
     fn into_sorted_vector(mut self) -> Vec<T> {
         let mut result = Vec::with_capacity(self.len());
-        while !self.heap.is_empty() {
+        while !self.is_empty() {
             result.push(self.heap.pop().unwrap().0);
         }
 
@@ -0,0 +1,125 @@
+// helpers shared by the utilities
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{self, AtomicUsize};
+
+pub mod random;
+pub mod regex;
+
+// bytes with an optional K, M or G suffix
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, shift) = match s.chars().last() {
+        Some('K' | 'k') => (&s[..s.len() - 1], 10),
+        Some('M' | 'm') => (&s[..s.len() - 1], 20),
+        Some('G' | 'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    match digits.parse::<usize>().map(|x| x.checked_mul(1 << shift)) {
+        Ok(Some(x)) => Ok(x),
+        _ => Err("expected a number of bytes, optionally followed by K, M or G".to_owned()),
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1000"), Ok(1000));
+    assert_eq!(parse_size("4k"), Ok(4 << 10));
+    assert_eq!(parse_size("256M"), Ok(256 << 20));
+    assert_eq!(parse_size("2G"), Ok(2 << 30));
+    assert!(parse_size("G").is_err());
+    assert!(parse_size("1T").is_err());
+}
+
+// the string as a JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string("a\"b\\c\td\u{1}"), r#""a\"b\\c\td\u0001""#);
+}
+
+// the field in double quotes if it contains the delimiter, a double quote or a line break
+pub fn quote_csv<'a>(field: &'a str, delim: &str) -> Cow<'a, str> {
+    match field.contains(delim) || field.contains(['"', '\n', '\r']) {
+        true => Cow::Owned(format!("\"{}\"", field.replace('"', "\"\""))),
+        false => Cow::Borrowed(field),
+    }
+}
+
+#[test]
+fn test_quote_csv() {
+    assert_eq!(quote_csv("b,c", ","), "\"b,c\"");
+    assert_eq!(quote_csv("say \"hi\"", ","), "\"say \"\"hi\"\"\"");
+    assert_eq!(quote_csv("a", ","), "a");
+}
+
+// compare numerically, with those that are not numbers after the numbers in byte order
+pub fn compare_numeric(a: &str, b: &str) -> Ordering {
+    match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.total_cmp(&y).then_with(|| a.cmp(b)),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+#[test]
+fn test_compare_numeric() {
+    let mut keys = vec!["10", "x", "9", "-1.5", "a", "1e1"];
+    keys.sort_by(|a, b| compare_numeric(a, b));
+    assert_eq!(keys, ["-1.5", "9", "10", "1e1", "a", "x"]);
+}
+
+static TEMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// a temporary file that is unlinked right away, so it is removed once closed,
+// even if the process is killed
+pub fn temp_file(tmpdir: &Path) -> io::Result<File> {
+    let path = tmpdir.join(format!(
+        "suputils-{}-{}",
+        std::process::id(),
+        TEMP_COUNT.fetch_add(1, atomic::Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[test]
+fn test_temp_file() {
+    use std::io::{Read, Seek, Write};
+    let tmpdir = std::env::temp_dir();
+    let mut file = temp_file(&tmpdir).unwrap();
+    let mut other = temp_file(&tmpdir).unwrap();
+    file.write_all(b"spilled").unwrap();
+    other.write_all(b"other").unwrap();
+    file.rewind().unwrap();
+    let mut content = String::new();
+    file.read_to_string(&mut content).unwrap();
+    assert_eq!(content, "spilled");
+    assert!(temp_file(Path::new("/nonexistent")).is_err());
+}
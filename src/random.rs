@@ -0,0 +1,79 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// a seed that differs on every run
+pub fn random_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    let nanos = now.map_or(0, |x| x.as_nanos() as u64);
+    nanos ^ ((std::process::id() as u64) << 32)
+}
+
+// xorshift64*, seeded through splitmix64 so that any seed, including 0, works
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Rng((z ^ (z >> 31)).max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    // uniform in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    // uniform in the open interval (0, 1)
+    pub fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+    }
+
+    // the number of failures before the first success in trials of the probability
+    pub fn geometric(&mut self, probability: f64) -> usize {
+        if probability >= 1.0 {
+            return 0;
+        }
+        // saturates at usize::MAX for a tiny probability
+        (self.uniform().ln() / (-probability).ln_1p()) as usize
+    }
+}
+
+// seeded with 0, for the same numbers on every run
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::new(0)
+    }
+}
+
+#[test]
+fn test_rng() {
+    // the same seed gives the same numbers
+    assert_eq!(Rng::new(7).next_u64(), Rng::new(7).next_u64());
+    assert_ne!(Rng::new(7).next_u64(), Rng::new(8).next_u64());
+
+    let mut rng = Rng::new(0);
+    let mut hits = [0; 4];
+    for _ in 0..10000 {
+        hits[rng.below(4)] += 1;
+    }
+    assert!(
+        hits.iter().all(|&n| (2250..2750).contains(&n)),
+        "{:?}",
+        hits
+    );
+
+    let mean = (0..10000).map(|_| rng.uniform()).sum::<f64>() / 10000.0;
+    assert!((0.49..0.51).contains(&mean), "{}", mean);
+
+    // the mean of the failures is (1 - p) / p
+    assert_eq!(rng.geometric(1.0), 0);
+    let mean = (0..10000).map(|_| rng.geometric(0.2)).sum::<usize>() as f64 / 10000.0;
+    assert!((3.8..4.2).contains(&mean), "{}", mean);
+}
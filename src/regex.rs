@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::ops::Range;
+
+// POSIX extended regular expression of libc
+pub struct Regex(Box<libc::regex_t>);
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let c_pattern = CString::new(pattern).map_err(|_| "the regex contains NUL".to_owned())?;
+        let mut regex = Box::new(unsafe { std::mem::zeroed::<libc::regex_t>() });
+        let code = unsafe { libc::regcomp(&mut *regex, c_pattern.as_ptr(), libc::REG_EXTENDED) };
+        if code != 0 {
+            let mut buf = [0u8; 256];
+            unsafe { libc::regerror(code, &*regex, buf.as_mut_ptr().cast(), buf.len()) };
+            let msg =
+                CStr::from_bytes_until_nul(&buf).map_or(Cow::Borrowed(""), CStr::to_string_lossy);
+            return Err(format!("invalid regex `{}`: {}", pattern, msg));
+        }
+        Ok(Regex(regex))
+    }
+
+    // the first match in the text, or its first parenthesized group if any
+    pub fn find<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let c_text = CString::new(text).ok()?;
+        let (_, token) = self.exec(&c_text, 0)?;
+        text.get(token)
+    }
+
+    // all non-overlapping, non-empty matches in the text, or their first parenthesized groups if any
+    pub fn find_all<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let mut found = Vec::new();
+        let c_text = match CString::new(text) {
+            Ok(x) => x,
+            Err(_) => return found,
+        };
+        let mut offset = 0;
+        while offset <= text.len() {
+            let (matched, token) = match self.exec(&c_text, offset) {
+                Some(x) => x,
+                None => break,
+            };
+            if let Some(token) = text.get(token) {
+                if !token.is_empty() {
+                    found.push(token);
+                }
+            }
+            // skip a character past an empty match
+            offset = match matched.is_empty() {
+                true => {
+                    let next = text.get(matched.end..).and_then(|rest| rest.chars().next());
+                    matched.end + next.map_or(1, char::len_utf8)
+                }
+                false => matched.end,
+            };
+        }
+        found
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match CString::new(text) {
+            Ok(c_text) => self.exec(&c_text, 0).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    // byte ranges of the first match from the offset and of its first parenthesized group,
+    // or of the match again if there is no group
+    fn exec(&self, c_text: &CStr, offset: usize) -> Option<(Range<usize>, Range<usize>)> {
+        let mut matches = [libc::regmatch_t {
+            rm_so: -1,
+            rm_eo: -1,
+        }; 2];
+        let code = unsafe {
+            libc::regexec(
+                &*self.0,
+                c_text.as_ptr().add(offset),
+                matches.len(),
+                matches.as_mut_ptr(),
+                if offset > 0 { libc::REG_NOTBOL } else { 0 },
+            )
+        };
+        if code != 0 {
+            return None;
+        }
+        let range = |m: libc::regmatch_t| offset + m.rm_so as usize..offset + m.rm_eo as usize;
+        let token = match matches[1].rm_so {
+            -1 => matches[0],
+            _ => matches[1],
+        };
+        Some((range(matches[0]), range(token)))
+    }
+}
+
+// regexec only reads the compiled pattern, so it may be called from several threads
+unsafe impl Send for Regex {}
+unsafe impl Sync for Regex {}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        unsafe { libc::regfree(&mut *self.0) }
+    }
+}
+
+#[test]
+fn test_regex() {
+    let regex = Regex::new("[0-9]+").unwrap();
+    assert_eq!(regex.find_all("a1 22, 333"), ["1", "22", "333"]);
+    assert!(regex.find_all("abc").is_empty());
+    let regex = Regex::new("x*").unwrap();
+    assert_eq!(regex.find_all("axxb x"), ["xx", "x"]);
+    let regex = Regex::new("^#([a-z]+)").unwrap();
+    assert_eq!(regex.find_all("#tag #not"), ["tag"]);
+    assert_eq!(regex.find("#tag #not"), Some("tag"));
+    assert_eq!(regex.find("tag"), None);
+    assert!(regex.is_match("#tag"));
+    assert!(!regex.is_match("tag"));
+    let regex = Regex::new("T([0-9]+):").unwrap();
+    assert_eq!(regex.find("2023-01-02T10:00"), Some("10"));
+    assert!(Regex::new("(").is_err());
+}